@@ -2,6 +2,8 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     let memory_file = if env::var("CARGO_FEATURE_NUCLEO_F401RE").is_ok() {
@@ -26,6 +28,28 @@ fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
         .write_all(&memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
+    if env::var("CARGO_FEATURE_USE_DEFMT").is_ok() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    // identifies the firmware, see `src/version.rs`
+    let git_hash = Command::new("git")
+        .args(&["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(|| "unknown".into(), |hash| hash.trim().to_string());
+    let build_time = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=BUILD_TIME={}", build_time);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
     println!("cargo:rerun-if-changed={}", memory_file);
     println!("cargo:rerun-if-changed=build.rs");
     Ok(())
@@ -0,0 +1,434 @@
+//! Host side of DFU 1.1: detaches a device running its application, then downloads or uploads a
+//! firmware through its DFU mode interface.
+//!
+//! Transfers go through `ControlTransfer`, so the same session drives a real device, through a USB
+//! library, or a `DFUModeClass` running in-process with `sim`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+pub use usbd_dfu::Error as Status;
+
+pub mod sim;
+
+/// Class requests to an interface.
+const CLASS_OUT: u8 = 0x21;
+const CLASS_IN: u8 = 0xA1;
+/// GET_DESCRIPTOR, to the device.
+const STANDARD_IN: u8 = 0x80;
+const GET_DESCRIPTOR: u8 = 6;
+const CONFIGURATION: u8 = 2;
+const INTERFACE: u8 = 4;
+
+/// Shortest wait, in milliseconds, between two DFU_GETSTATUS while the device is busy. A device
+/// reporting a zero bwPollTimeout is not flooded with requests.
+const MIN_POLL_INTERVAL: u32 = 10;
+
+struct Request;
+impl Request {
+    const DFU_DETACH: u8 = 0;
+    const DFU_DNLOAD: u8 = 1;
+    const DFU_UPLOAD: u8 = 2;
+    const DFU_GETSTATUS: u8 = 3;
+    const DFU_CLRSTATUS: u8 = 4;
+    const DFU_GETSTATE: u8 = 5;
+    const DFU_ABORT: u8 = 6;
+}
+
+/// Failure of a single control transfer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransferError<E> {
+    /// The device stalled the request.
+    Stall,
+    /// The backend could not complete the transfer.
+    Io(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for TransferError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Stall => write!(f, "the device stalled the request"),
+            TransferError::Io(e) => write!(f, "the transfer failed: {:?}", e),
+        }
+    }
+}
+impl<E: fmt::Debug> std::error::Error for TransferError<E> {}
+
+/// Performs control transfers on endpoint 0 of a device.
+pub trait ControlTransfer {
+    type Error: fmt::Debug;
+
+    /// Performs a transfer with a data stage from the device, returning the number of bytes
+    /// received in `buf`. `buf.len()` is sent as wLength.
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransferError<Self::Error>>;
+
+    /// Performs a transfer with an optional data stage from the host.
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError<Self::Error>>;
+
+    /// Resets the bus, the device then re-enumerates.
+    fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Leaves the device to its work for `ms` milliseconds.
+    fn wait(&mut self, ms: u32) {
+        std::thread::sleep(Duration::from_millis(ms.into()));
+    }
+}
+
+/// State reported by DFU_GETSTATUS and DFU_GETSTATE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DfuDnloadSync = 3,
+    DfuDnloadBusy = 4,
+    DfuDnloadIdle = 5,
+    DfuManifestSync = 6,
+    DfuManifest = 7,
+    DfuManifestWaitReset = 8,
+    DfuUploadIdle = 9,
+    DfuError = 10,
+}
+impl TryFrom<u8> for DeviceState {
+    type Error = u8;
+
+    fn try_from(state: u8) -> Result<Self, u8> {
+        use DeviceState::*;
+        Ok(match state {
+            0 => AppIdle,
+            1 => AppDetach,
+            2 => DfuIdle,
+            3 => DfuDnloadSync,
+            4 => DfuDnloadBusy,
+            5 => DfuDnloadIdle,
+            6 => DfuManifestSync,
+            7 => DfuManifest,
+            8 => DfuManifestWaitReset,
+            9 => DfuUploadIdle,
+            10 => DfuError,
+            _ => return Err(state),
+        })
+    }
+}
+
+/// Reply to DFU_GETSTATUS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStatus {
+    /// `None` when the device reports `OK`.
+    pub status: Option<Status>,
+    /// Minimum time, in milliseconds, the host waits before its next DFU_GETSTATUS.
+    pub poll_timeout: u32,
+    pub state: DeviceState,
+    /// Index of a string descriptor describing the status.
+    pub string_index: u8,
+}
+impl DeviceStatus {
+    fn parse(reply: &[u8]) -> Option<Self> {
+        if reply.len() != 6 {
+            return None;
+        }
+        let status = match reply[0] {
+            0 => None,
+            code => Some(Status::try_from(code).ok()?),
+        };
+        Some(Self {
+            status,
+            poll_timeout: u32::from_le_bytes([reply[1], reply[2], reply[3], 0]),
+            state: DeviceState::try_from(reply[4]).ok()?,
+            string_index: reply[5],
+        })
+    }
+}
+
+/// DFU functional descriptor of the interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionalDescriptor {
+    pub will_detach: bool,
+    pub is_manifestation_tolerant: bool,
+    pub can_upload: bool,
+    pub can_download: bool,
+    pub detach_timeout: u16,
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+impl FunctionalDescriptor {
+    /// Finds the descriptor following the DFU interface `interface` in a configuration descriptor.
+    pub fn find(configuration: &[u8], interface: u8) -> Option<Self> {
+        let mut descriptors = std::iter::successors(Some(configuration), |rest| {
+            let len = usize::from(*rest.first()?);
+            rest.get(len.max(1)..).filter(|rest| !rest.is_empty())
+        });
+        descriptors.find(|d| {
+            d.len() >= 9
+                && d[1] == INTERFACE
+                && d[2] == interface
+                && d[5] == usbd_dfu::USB_CLASS_DFU
+                && d[6] == usbd_dfu::USB_SUB_CLASS_DFU
+        })?;
+        let d = descriptors
+            .take_while(|d| d.get(1) != Some(&INTERFACE))
+            .find(|d| d.len() >= 9 && d[1] == usbd_dfu::DFU_FUNCTIONAL)?;
+        Some(Self {
+            will_detach: d[2] & 0b1000 != 0,
+            is_manifestation_tolerant: d[2] & 0b0100 != 0,
+            can_upload: d[2] & 0b0010 != 0,
+            can_download: d[2] & 0b0001 != 0,
+            detach_timeout: u16::from_le_bytes([d[3], d[4]]),
+            transfer_size: u16::from_le_bytes([d[5], d[6]]),
+            dfu_version: u16::from_le_bytes([d[7], d[8]]),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Transfer(TransferError<E>),
+    /// The device has no DFU interface with that number.
+    NoInterface,
+    /// The device reported an error, it stays in dfuERROR until DFU_CLRSTATUS.
+    Device(Status),
+    /// The device is in a state the operation cannot start from or continue in.
+    UnexpectedState(DeviceState),
+    /// The device replied with a malformed status or state.
+    InvalidReply,
+    /// The functional descriptor does not allow the operation.
+    Unsupported,
+}
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transfer(e) => e.fmt(f),
+            Error::NoInterface => write!(f, "the device has no such DFU interface"),
+            Error::Device(status) => write!(f, "the device reported an error: {:?}", status),
+            Error::UnexpectedState(state) => write!(f, "unexpected device state: {:?}", state),
+            Error::InvalidReply => write!(f, "the device sent a malformed reply"),
+            Error::Unsupported => write!(f, "the device does not support the operation"),
+        }
+    }
+}
+impl<E: fmt::Debug> std::error::Error for Error<E> {}
+
+impl<E> From<TransferError<E>> for Error<E> {
+    fn from(e: TransferError<E>) -> Self {
+        Error::Transfer(e)
+    }
+}
+
+/// A DFU interface of a device.
+pub struct Dfu<T: ControlTransfer> {
+    transport: T,
+    interface: u8,
+    descriptor: FunctionalDescriptor,
+}
+impl<T: ControlTransfer> Dfu<T> {
+    /// Reads the functional descriptor of `interface` from the active configuration.
+    pub fn open(mut transport: T, interface: u8) -> Result<Self, Error<T::Error>> {
+        let mut configuration = [0; 512];
+        let len = transport.control_in(
+            STANDARD_IN,
+            GET_DESCRIPTOR,
+            u16::from(CONFIGURATION) << 8,
+            0,
+            &mut configuration,
+        )?;
+        let descriptor = FunctionalDescriptor::find(&configuration[..len], interface)
+            .ok_or(Error::NoInterface)?;
+        Ok(Self {
+            transport,
+            interface,
+            descriptor,
+        })
+    }
+
+    pub fn descriptor(&self) -> &FunctionalDescriptor {
+        &self.descriptor
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Asks a device running its application to enter DFU mode, then resets it unless it detaches
+    /// on its own. The device re-enumerates in DFU mode, to be opened again.
+    pub fn detach(&mut self) -> Result<(), Error<T::Error>> {
+        let timeout = self.descriptor.detach_timeout;
+        self.out(Request::DFU_DETACH, timeout, &[])?;
+        if !self.descriptor.will_detach {
+            self.transport.reset().map_err(TransferError::Io)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_status(&mut self) -> Result<DeviceStatus, Error<T::Error>> {
+        let mut reply = [0; 6];
+        let len = self.transport.control_in(
+            CLASS_IN,
+            Request::DFU_GETSTATUS,
+            0,
+            self.interface.into(),
+            &mut reply,
+        )?;
+        DeviceStatus::parse(&reply[..len]).ok_or(Error::InvalidReply)
+    }
+
+    pub fn get_state(&mut self) -> Result<DeviceState, Error<T::Error>> {
+        let mut reply = [0; 1];
+        let len = self.transport.control_in(
+            CLASS_IN,
+            Request::DFU_GETSTATE,
+            0,
+            self.interface.into(),
+            &mut reply,
+        )?;
+        match reply[..len] {
+            [state] => DeviceState::try_from(state).map_err(|_| Error::InvalidReply),
+            _ => Err(Error::InvalidReply),
+        }
+    }
+
+    pub fn clear_status(&mut self) -> Result<(), Error<T::Error>> {
+        self.out(Request::DFU_CLRSTATUS, 0, &[])
+    }
+
+    pub fn abort(&mut self) -> Result<(), Error<T::Error>> {
+        self.out(Request::DFU_ABORT, 0, &[])
+    }
+
+    /// Brings the device back to dfuIDLE: clears a pending error and aborts an unfinished transfer.
+    pub fn recover(&mut self) -> Result<(), Error<T::Error>> {
+        // a stalled abort leaves the device in dfuERROR, cleared on the next round
+        for _ in 0..2 {
+            match self.get_status()?.state {
+                DeviceState::DfuIdle => return Ok(()),
+                DeviceState::DfuError => self.clear_status()?,
+                DeviceState::DfuDnloadIdle | DeviceState::DfuUploadIdle => match self.abort() {
+                    Ok(()) | Err(Error::Transfer(TransferError::Stall)) => {}
+                    Err(e) => return Err(e),
+                },
+                state => return Err(Error::UnexpectedState(state)),
+            }
+        }
+        match self.get_state()? {
+            DeviceState::DfuIdle => Ok(()),
+            state => Err(Error::UnexpectedState(state)),
+        }
+    }
+
+    /// Downloads `image` then waits for its manifestation. Devices that are not manifestation
+    /// tolerant are reset once done. Devices stall an empty image.
+    pub fn download(&mut self, image: &[u8]) -> Result<(), Error<T::Error>> {
+        if !self.descriptor.can_download {
+            return Err(Error::Unsupported);
+        }
+        self.recover()?;
+
+        let mut block_number = 0u16;
+        for block in image.chunks(self.transfer_size()) {
+            self.out(Request::DFU_DNLOAD, block_number, block)?;
+            self.wait_while(DeviceState::DfuDnloadBusy, DeviceState::DfuDnloadIdle)?;
+            block_number = block_number.wrapping_add(1);
+        }
+
+        // an empty block announces the end of the image
+        self.out(Request::DFU_DNLOAD, block_number, &[])?;
+        if self.descriptor.is_manifestation_tolerant {
+            self.wait_while(DeviceState::DfuManifest, DeviceState::DfuIdle)?;
+        } else {
+            let status = self.get_status()?;
+            match (status.status, status.state) {
+                (Some(e), _) => return Err(Error::Device(e)),
+                (None, DeviceState::DfuManifest) => self.transport.wait(status.poll_timeout),
+                (None, state) => return Err(Error::UnexpectedState(state)),
+            }
+            self.transport.reset().map_err(TransferError::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the firmware back from the device.
+    pub fn upload(&mut self) -> Result<Vec<u8>, Error<T::Error>> {
+        if !self.descriptor.can_upload {
+            return Err(Error::Unsupported);
+        }
+        self.recover()?;
+
+        let mut image = Vec::new();
+        let mut block = vec![0; self.transfer_size()];
+        let mut block_number = 0u16;
+        loop {
+            let len = self.transport.control_in(
+                CLASS_IN,
+                Request::DFU_UPLOAD,
+                block_number,
+                self.interface.into(),
+                &mut block,
+            )?;
+            image.extend_from_slice(&block[..len]);
+            // a short block ends the upload
+            if len < block.len() {
+                break;
+            }
+            block_number = block_number.wrapping_add(1);
+        }
+
+        match self.get_status()? {
+            DeviceStatus {
+                status: Some(e), ..
+            } => Err(Error::Device(e)),
+            DeviceStatus {
+                state: DeviceState::DfuIdle,
+                ..
+            } => Ok(image),
+            DeviceStatus { state, .. } => Err(Error::UnexpectedState(state)),
+        }
+    }
+
+    /// Polls the status, honouring the poll timeout, for as long as the device reports `busy`.
+    fn wait_while(&mut self, busy: DeviceState, done: DeviceState) -> Result<(), Error<T::Error>> {
+        loop {
+            let status = self.get_status()?;
+            if let Some(e) = status.status {
+                return Err(Error::Device(e));
+            }
+            match status.state {
+                state if state == done => return Ok(()),
+                state if state == busy => {
+                    let ms = status.poll_timeout.max(MIN_POLL_INTERVAL);
+                    self.transport.wait(ms)
+                }
+                // the device moves back to its sync state once the poll timeout elapsed
+                DeviceState::DfuDnloadSync | DeviceState::DfuManifestSync => {}
+                state => return Err(Error::UnexpectedState(state)),
+            }
+        }
+    }
+
+    fn transfer_size(&self) -> usize {
+        usize::from(self.descriptor.transfer_size).max(1)
+    }
+
+    fn out(&mut self, request: u8, value: u16, data: &[u8]) -> Result<(), Error<T::Error>> {
+        self.transport
+            .control_out(CLASS_OUT, request, value, self.interface.into(), data)?;
+        Ok(())
+    }
+}
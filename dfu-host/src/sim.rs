@@ -0,0 +1,315 @@
+//! In-process backend: a DFU class of `usbd-dfu` runs on a simulated bus, the session playing the
+//! host on its endpoint 0. Time only moves forward in `ControlTransfer::wait`.
+//!
+//! The bus and the transfer functions are also used on their own by the tests of `usbd-dfu`, which
+//! drive several classes at once.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use usb_device::bus::PollResult;
+use usb_device::class_prelude::*;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::{UsbDirection, UsbError};
+use usbd_dfu::{mode, runtime, Instant, Monotonic};
+
+use super::{ControlTransfer, TransferError};
+
+pub const MAX_PACKET_SIZE: usize = 64;
+
+/// Number of device polls a transfer may take before it is given up.
+const MAX_POLLS: usize = 1000;
+
+#[derive(Default)]
+struct Endpoint0 {
+    setup: Option<[u8; 8]>,
+    out: VecDeque<Vec<u8>>,
+    /// Packets written by the device during the current transfer.
+    written: Vec<Vec<u8>>,
+    in_complete: bool,
+    stalled: bool,
+}
+
+#[derive(Default)]
+pub struct SimBus {
+    ep0: Mutex<Endpoint0>,
+    next_ep: Mutex<u8>,
+    reset: Mutex<bool>,
+}
+impl UsbBus for SimBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        if let Some(addr) = ep_addr {
+            return Ok(addr);
+        }
+        let mut next = self.next_ep.lock().unwrap();
+        *next += 1;
+        Ok(EndpointAddress::from_parts(usize::from(*next), ep_dir))
+    }
+    fn enable(&mut self) {}
+    fn reset(&self) {
+        *self.ep0.lock().unwrap() = Endpoint0::default();
+    }
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        if ep_addr.index() != 0 {
+            return Err(UsbError::WouldBlock);
+        }
+        let mut ep0 = self.ep0.lock().unwrap();
+        ep0.written.push(buf.to_vec());
+        ep0.in_complete = true;
+        Ok(buf.len())
+    }
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        if ep_addr.index() != 0 {
+            return Err(UsbError::WouldBlock);
+        }
+        let mut ep0 = self.ep0.lock().unwrap();
+        let packet = match ep0.setup.take() {
+            Some(setup) => setup.to_vec(),
+            None => ep0.out.pop_front().ok_or(UsbError::WouldBlock)?,
+        };
+        let dst = buf
+            .get_mut(..packet.len())
+            .ok_or(UsbError::BufferOverflow)?;
+        dst.copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if ep_addr.index() == 0 {
+            self.ep0.lock().unwrap().stalled = stalled;
+        }
+    }
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        ep_addr.index() == 0 && self.ep0.lock().unwrap().stalled
+    }
+    fn suspend(&self) {}
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        if std::mem::take(&mut *self.reset.lock().unwrap()) {
+            return PollResult::Reset;
+        }
+        let mut ep0 = self.ep0.lock().unwrap();
+        let ep_setup = ep0.setup.is_some() as u16;
+        let ep_out = (ep0.setup.is_some() || !ep0.out.is_empty()) as u16;
+        let ep_in_complete = std::mem::take(&mut ep0.in_complete) as u16;
+        if ep_setup | ep_out | ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+/// Simulated time, shared between the device and its classes.
+#[derive(Clone, Default)]
+pub struct SimClock(Rc<Cell<u32>>);
+impl SimClock {
+    fn advance(&self, ms: u32) {
+        self.0.set(self.0.get().wrapping_add(ms));
+    }
+}
+impl Monotonic for SimClock {
+    fn now(&self) -> Instant {
+        Instant::from_millis(self.0.get())
+    }
+}
+
+/// A class run by the simulated device, polled as the firmware would.
+pub trait Function: UsbClass<SimBus> {
+    fn poll(&mut self);
+}
+impl<H: mode::DeviceFirmwareUpgrade, M: Monotonic> Function for mode::DFUModeClass<H, SimBus, M> {
+    fn poll(&mut self) {
+        mode::DFUModeClass::poll(self)
+    }
+}
+impl<H: runtime::DeviceFirmwareUpgrade, M: Monotonic> Function for runtime::DFURuntimeClass<H, M> {
+    fn poll(&mut self) {
+        runtime::DFURuntimeClass::poll(self)
+    }
+}
+
+/// The device did not complete a transfer.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timeout;
+
+pub fn allocator() -> UsbBusAllocator<SimBus> {
+    UsbBusAllocator::new(SimBus::default())
+}
+
+pub fn device(alloc: &UsbBusAllocator<SimBus>) -> UsbDevice<'_, SimBus> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27dd))
+        .max_packet_size_0(MAX_PACKET_SIZE as u8)
+        .build()
+}
+
+/// Builds a SETUP packet.
+pub fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let mut packet = [request_type, request, 0, 0, 0, 0, 0, 0];
+    packet[2..4].copy_from_slice(&value.to_le_bytes());
+    packet[4..6].copy_from_slice(&index.to_le_bytes());
+    packet[6..8].copy_from_slice(&length.to_le_bytes());
+    packet
+}
+
+fn start(dev: &mut UsbDevice<'_, SimBus>, setup: [u8; 8], data: &[u8]) {
+    let mut ep0 = dev.bus().ep0.lock().unwrap();
+    ep0.setup = Some(setup);
+    ep0.out = data.chunks(MAX_PACKET_SIZE).map(<[u8]>::to_vec).collect();
+    ep0.written.clear();
+    ep0.stalled = false;
+}
+
+/// Performs a control transfer with a data stage from the device, polling `dev` and `classes` as
+/// the firmware would. The reply is at most wLength long.
+pub fn control_in(
+    dev: &mut UsbDevice<'_, SimBus>,
+    classes: &mut [&mut dyn UsbClass<SimBus>],
+    setup: [u8; 8],
+) -> Result<Vec<u8>, TransferError<Timeout>> {
+    let length = usize::from(u16::from_le_bytes([setup[6], setup[7]]));
+    start(dev, setup, &[]);
+    for _ in 0..MAX_POLLS {
+        dev.poll(classes);
+
+        let mut ep0 = dev.bus().ep0.lock().unwrap();
+        if ep0.stalled {
+            return Err(TransferError::Stall);
+        }
+        let mut data: Vec<u8> = ep0.written.concat();
+        let is_short = ep0
+            .written
+            .last()
+            .is_some_and(|packet| packet.len() < MAX_PACKET_SIZE);
+        if data.len() >= length || is_short {
+            data.truncate(length);
+            // status stage
+            ep0.out.push_back(Vec::new());
+            drop(ep0);
+            dev.poll(classes);
+            return Ok(data);
+        }
+    }
+    Err(TransferError::Io(Timeout))
+}
+
+/// Performs a control transfer with an optional data stage from the host.
+pub fn control_out(
+    dev: &mut UsbDevice<'_, SimBus>,
+    classes: &mut [&mut dyn UsbClass<SimBus>],
+    setup: [u8; 8],
+    data: &[u8],
+) -> Result<(), TransferError<Timeout>> {
+    start(dev, setup, data);
+    for _ in 0..MAX_POLLS {
+        dev.poll(classes);
+
+        let ep0 = dev.bus().ep0.lock().unwrap();
+        if ep0.stalled {
+            return Err(TransferError::Stall);
+        }
+        // the status stage is a zero length packet from the device
+        if ep0.written.iter().any(Vec::is_empty) {
+            return Ok(());
+        }
+    }
+    Err(TransferError::Io(Timeout))
+}
+
+/// A device exposing a single function.
+pub struct Simulated<C: Function> {
+    device: UsbDevice<'static, SimBus>,
+    class: C,
+    clock: SimClock,
+}
+impl<C: Function> Simulated<C> {
+    /// Builds the device around the class returned by `class`. The bus allocator is leaked, which
+    /// is of no concern to the short lived processes this is meant for.
+    pub fn new(class: impl FnOnce(&'static UsbBusAllocator<SimBus>, SimClock) -> C) -> Self {
+        let alloc = Box::leak(Box::new(allocator()));
+        let clock = SimClock::default();
+        let class = class(alloc, clock.clone());
+        Self {
+            device: device(alloc),
+            class,
+            clock,
+        }
+    }
+
+    pub fn class(&mut self) -> &mut C {
+        &mut self.class
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn poll(&mut self) {
+        self.device.poll(&mut [&mut self.class]);
+    }
+}
+
+impl<C: Function> ControlTransfer for Simulated<C> {
+    type Error = Timeout;
+
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransferError<Timeout>> {
+        let length = buf.len().min(usize::from(u16::MAX)) as u16;
+        let setup = setup(request_type, request, value, index, length);
+        let data = control_in(&mut self.device, &mut [&mut self.class], setup)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), TransferError<Timeout>> {
+        let length = u16::try_from(data.len()).map_err(|_| TransferError::Io(Timeout))?;
+        let setup = setup(request_type, request, value, index, length);
+        control_out(&mut self.device, &mut [&mut self.class], setup, data)
+    }
+
+    fn reset(&mut self) -> Result<(), Timeout> {
+        *self.device.bus().reset.lock().unwrap() = true;
+        self.poll();
+        Ok(())
+    }
+
+    /// Advances the clock a millisecond at a time, polling the class and the device in between.
+    /// The device is polled at least once so that a zero timeout still lets it progress.
+    fn wait(&mut self, ms: u32) {
+        for _ in 0..ms.max(1) {
+            self.clock.advance(1);
+            Function::poll(&mut self.class);
+            self.poll();
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use dfu_host::sim::{SimBus, SimClock, Simulated};
+use dfu_host::{ControlTransfer, DeviceState, Dfu, Error, Status};
+use usbd_dfu::mode::{self, DFUModeClass};
+use usbd_dfu::runtime::{self, DFURuntimeClass};
+use usbd_dfu::{Capabilities, State};
+
+const POLL_TIMEOUT: u32 = 20;
+/// Shortest wait of the host between two DFU_GETSTATUS while the device is busy.
+const MIN_POLL_INTERVAL: u32 = 10;
+/// Number of device polls each block keeps the device busy for.
+const BUSY_POLLS: u32 = 3;
+const MANIFEST_POLLS: u32 = 7;
+
+/// Stores the downloaded image in RAM, uploads the last manifested one.
+#[derive(Default)]
+struct Ram<const TOLERANT: bool> {
+    image: Vec<u8>,
+    received: Vec<u8>,
+    busy: u32,
+    manifesting: Option<u32>,
+    fail_block: Option<u16>,
+    /// Overrides `POLL_TIMEOUT`.
+    poll_timeout: Option<u32>,
+}
+impl<const TOLERANT: bool> Capabilities for Ram<TOLERANT> {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = TOLERANT;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = 128;
+}
+impl<const TOLERANT: bool> mode::DeviceFirmwareUpgrade for Ram<TOLERANT> {
+    const POLL_TIMEOUT: u32 = POLL_TIMEOUT;
+
+    fn poll_timeout(&mut self) -> u32 {
+        self.poll_timeout.unwrap_or(POLL_TIMEOUT)
+    }
+
+    fn is_firmware_valid(&mut self) -> bool {
+        true
+    }
+    fn is_transfer_complete(&mut self) -> usbd_dfu::Result<bool> {
+        Ok(self.busy == 0)
+    }
+    fn is_manifestation_in_progress(&mut self) -> usbd_dfu::Result<bool> {
+        if self.manifesting.is_none() {
+            self.image = std::mem::take(&mut self.received);
+            self.manifesting = Some(MANIFEST_POLLS);
+        }
+        Ok(self.manifesting > Some(0))
+    }
+    fn poll(&mut self) -> usbd_dfu::Result<()> {
+        self.busy = self.busy.saturating_sub(1);
+        if let Some(remaining) = &mut self.manifesting {
+            *remaining = remaining.saturating_sub(1);
+        }
+        Ok(())
+    }
+    fn upload(&mut self, block_number: u16, buf: &mut [u8]) -> usbd_dfu::Result<usize> {
+        let start = usize::from(block_number) * usize::from(Self::TRANSFER_SIZE);
+        let block = self.image.get(start..).unwrap_or_default();
+        let len = block.len().min(buf.len());
+        buf[..len].copy_from_slice(&block[..len]);
+        Ok(len)
+    }
+    fn download(&mut self, block_number: u16, buf: &[u8]) -> usbd_dfu::Result<()> {
+        if self.fail_block == Some(block_number) {
+            return Err(usbd_dfu::Error::Address);
+        }
+        if block_number == 0 {
+            self.received.clear();
+            self.manifesting = None;
+        }
+        self.received.extend_from_slice(buf);
+        self.busy = BUSY_POLLS;
+        Ok(())
+    }
+}
+
+type Device<const TOLERANT: bool> = Simulated<DFUModeClass<Ram<TOLERANT>, SimBus, SimClock>>;
+
+fn open<const TOLERANT: bool>() -> Dfu<Device<TOLERANT>> {
+    let device = Simulated::new(|alloc, clock| DFUModeClass::new(alloc, Ram::default(), clock));
+    Dfu::open(device, 0).expect("DFU interface")
+}
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn reads_the_functional_descriptor() {
+    let dfu = open::<true>();
+    let descriptor = dfu.descriptor();
+    assert!(descriptor.is_manifestation_tolerant);
+    assert!(descriptor.can_upload && descriptor.can_download);
+    assert_eq!(descriptor.transfer_size, 128);
+    assert_eq!(descriptor.detach_timeout, 250);
+}
+
+#[test]
+fn uploads_what_was_downloaded() {
+    for len in [1, 128, 256, 1000] {
+        let mut dfu = open::<true>();
+        let firmware = image(len);
+        dfu.download(&firmware).unwrap();
+        assert_eq!(dfu.get_state(), Ok(DeviceState::DfuIdle));
+        assert_eq!(dfu.transport().class().handler().image, firmware);
+        assert_eq!(dfu.upload().unwrap(), firmware);
+    }
+}
+
+#[test]
+fn download_waits_for_the_poll_timeout() {
+    let mut dfu = open::<true>();
+    let start = dfu.transport().now();
+    dfu.download(&image(1000)).unwrap();
+
+    let elapsed = dfu.transport().now().checked_duration_since(start).unwrap();
+    // 8 busy blocks then the manifestation, each reported busy once
+    assert!(elapsed >= 9 * POLL_TIMEOUT, "{} ms", elapsed);
+    assert!(elapsed <= 2 * 9 * POLL_TIMEOUT, "{} ms", elapsed);
+}
+
+#[test]
+fn zero_poll_timeout_still_waits_between_requests() {
+    let mut dfu = open::<true>();
+    dfu.transport().class().handler().poll_timeout = Some(0);
+    let start = dfu.transport().now();
+    dfu.download(&image(1000)).unwrap();
+
+    let elapsed = dfu.transport().now().checked_duration_since(start).unwrap();
+    assert!(elapsed >= 9 * MIN_POLL_INTERVAL, "{} ms", elapsed);
+}
+
+#[test]
+fn device_error_is_reported_then_cleared() {
+    let mut dfu = open::<true>();
+    dfu.transport().class().handler().fail_block = Some(2);
+    assert_eq!(
+        dfu.download(&image(1000)),
+        Err(Error::Device(Status::Address))
+    );
+    assert_eq!(
+        dfu.transport().class().state(),
+        State::DfuError(usbd_dfu::Error::Address)
+    );
+
+    dfu.transport().class().handler().fail_block = None;
+    dfu.download(&image(1000)).unwrap();
+    assert_eq!(dfu.upload().unwrap(), image(1000));
+}
+
+#[test]
+fn recovers_from_an_unfinished_upload() {
+    let mut dfu = open::<true>();
+    dfu.download(&image(1000)).unwrap();
+
+    let mut block = [0; 128];
+    let len = dfu
+        .transport()
+        .control_in(0xA1, 2, 0, 0, &mut block)
+        .unwrap();
+    assert_eq!(len, 128);
+    assert_eq!(dfu.get_state(), Ok(DeviceState::DfuUploadIdle));
+
+    dfu.recover().unwrap();
+    assert_eq!(dfu.get_state(), Ok(DeviceState::DfuIdle));
+}
+
+#[test]
+fn recovers_from_an_unfinished_download() {
+    let mut dfu = open::<true>();
+    dfu.transport()
+        .control_out(0x21, 1, 0, 0, &[0; 128])
+        .unwrap();
+    dfu.transport().wait(POLL_TIMEOUT);
+    while dfu.get_status().unwrap().state != DeviceState::DfuDnloadIdle {
+        dfu.transport().wait(POLL_TIMEOUT);
+    }
+
+    dfu.recover().unwrap();
+    assert_eq!(dfu.get_state(), Ok(DeviceState::DfuIdle));
+}
+
+#[test]
+fn resets_a_device_that_is_not_manifestation_tolerant() {
+    let mut dfu = open::<false>();
+    dfu.download(&image(300)).unwrap();
+    let class = dfu.transport().class();
+    assert_eq!(class.state(), State::DfuManifestWaitReset);
+    assert_eq!(class.handler().image, image(300));
+}
+
+#[derive(Default)]
+struct Application {
+    detach_timeout: Option<u16>,
+    was_reset: bool,
+}
+impl Capabilities for Application {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = true;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = 128;
+}
+impl runtime::DeviceFirmwareUpgrade for Application {
+    fn on_reset(&mut self) {
+        self.was_reset = true;
+    }
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.detach_timeout = Some(timeout_ms);
+    }
+}
+
+#[test]
+fn detach_resets_the_device() {
+    let device =
+        Simulated::new(|alloc, clock| DFURuntimeClass::new(alloc, Application::default(), clock));
+    let mut dfu = Dfu::open(device, 0).unwrap();
+    assert_eq!(dfu.get_state(), Ok(DeviceState::AppIdle));
+
+    dfu.detach().unwrap();
+    let handler = dfu.transport().class().handler();
+    assert_eq!(handler.detach_timeout, Some(250));
+    assert!(handler.was_reset);
+}
+
+#[test]
+fn missing_interface_is_reported() {
+    let device =
+        Simulated::new(|alloc, clock| DFUModeClass::new(alloc, Ram::<true>::default(), clock));
+    assert!(matches!(Dfu::open(device, 1), Err(Error::NoInterface)));
+}
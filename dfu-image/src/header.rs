@@ -0,0 +1,35 @@
+/// Offset of the `ImageHeader` in the application image, right after the vector table. See the
+/// application's memory script.
+pub const IMAGE_HEADER_OFFSET: usize = 0x200;
+const IMAGE_HEADER_MAGIC: u32 = 0x4844_4D49; // "IMDH"
+
+/// Information the application image carries for the bootloader.
+#[repr(C)]
+pub struct ImageHeader {
+    magic: u32,
+    /// Raised when a release fixes a vulnerability. Once an image is confirmed, images with a lower
+    /// security version are refused.
+    pub security_version: u32,
+}
+impl ImageHeader {
+    pub const fn new(security_version: u32) -> Self {
+        Self {
+            magic: IMAGE_HEADER_MAGIC,
+            security_version,
+        }
+    }
+
+    /// Security version of `image`. Images without a header have a security version of 0.
+    pub fn security_version_of(image: &[u8]) -> u32 {
+        let field = |offset: usize| {
+            let start = IMAGE_HEADER_OFFSET + offset;
+            image
+                .get(start..start + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        match (field(0), field(4)) {
+            (Some(IMAGE_HEADER_MAGIC), Some(security_version)) => security_version,
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Formats shared by the bootloader and the host tools that build its images.
+
+#![no_std]
+
+mod header;
+mod manifest;
+
+pub use header::*;
+pub use manifest::*;
+
+/// CRC-32 as used by Ethernet and zip.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
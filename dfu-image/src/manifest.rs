@@ -0,0 +1,175 @@
+//! On-flash description of the installed image. It is serialised field by field, little endian:
+//!
+//! | Offset | Size | Field
+//! |--------|------|------
+//! |      0 |    4 | magic, `MANIFEST_MAGIC`
+//! |      4 |    2 | format version, `MANIFEST_VERSION`
+//! |      6 |    2 | reserved, 0
+//! |      8 |    4 | CRC-32 of the bytes following it
+//! |     12 |    4 | image length
+//! |     16 |    2 | image type, see `ImageType`
+//! |     18 |    2 | flags, see `FLAG_SIGNED`
+//! |     20 |    4 | load address
+//! |     24 |    4 | security version, see `ImageHeader`
+//! |     28 |    1 | hash algorithm, see `HashAlgorithm`
+//! |     29 |    3 | reserved, 0
+//! |     32 |   32 | hash, zero padded
+//!
+//! A new format gets a new version: devices refuse manifests they don't know rather than misread
+//! them.
+//!
+//! Images built by the host start with their manifest, followed by the signature if there is one,
+//! so the device knows what it receives before anything is erased.
+
+pub const MANIFEST_MAGIC: u32 = 0x4E41_4D44; // "DMAN"
+pub const MANIFEST_VERSION: u16 = 1;
+pub const MANIFEST_LENGTH: usize = 64;
+const MAX_HASH_LENGTH: usize = 32;
+
+/// The manifest is followed by a HMAC-SHA256 of its bytes, `SIGNATURE_LENGTH` long.
+pub const FLAG_SIGNED: u16 = 1 << 0;
+pub const SIGNATURE_LENGTH: usize = 32;
+const CRC_END: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ManifestError {
+    /// Nothing that looks like a manifest, e.g. blank flash.
+    Magic,
+    /// Written by a newer or an older bootloader.
+    Version(u16),
+    Crc,
+    ImageType(u16),
+    HashAlgorithm(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageType {
+    Application = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HashAlgorithm {
+    Sha1 = 1,
+    Sha256 = 2,
+}
+impl HashAlgorithm {
+    pub fn length(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Manifest {
+    pub image_length: u32,
+    pub image_type: ImageType,
+    pub flags: u16,
+    pub load_address: u32,
+    pub security_version: u32,
+    pub hash_algorithm: HashAlgorithm,
+    hash: [u8; MAX_HASH_LENGTH],
+}
+impl Manifest {
+    /// `hash` must be as long as `hash_algorithm` produces.
+    pub fn new(
+        image_type: ImageType,
+        load_address: u32,
+        image_length: u32,
+        security_version: u32,
+        hash_algorithm: HashAlgorithm,
+        hash: &[u8],
+    ) -> Self {
+        let mut manifest = Self {
+            image_length,
+            image_type,
+            flags: 0,
+            load_address,
+            security_version,
+            hash_algorithm,
+            hash: [0; MAX_HASH_LENGTH],
+        };
+        manifest.hash[..hash_algorithm.length()].copy_from_slice(hash);
+        manifest
+    }
+
+    /// Size of the manifest and of the signature that follows it, if any.
+    pub fn encoded_length(&self) -> usize {
+        if self.flags & FLAG_SIGNED != 0 {
+            MANIFEST_LENGTH + SIGNATURE_LENGTH
+        } else {
+            MANIFEST_LENGTH
+        }
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        &self.hash[..self.hash_algorithm.length()]
+    }
+
+    pub fn to_bytes(&self) -> [u8; MANIFEST_LENGTH] {
+        let mut bytes = [0; MANIFEST_LENGTH];
+        bytes[0..4].copy_from_slice(&MANIFEST_MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&MANIFEST_VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.image_length.to_le_bytes());
+        bytes[16..18].copy_from_slice(&(self.image_type as u16).to_le_bytes());
+        bytes[18..20].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.load_address.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.security_version.to_le_bytes());
+        bytes[28] = self.hash_algorithm as u8;
+        bytes[32..].copy_from_slice(&self.hash);
+
+        let crc = crate::crc32(&bytes[CRC_END..]);
+        bytes[8..12].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; MANIFEST_LENGTH]) -> Result<Self, ManifestError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+
+        if u32_at(0) != MANIFEST_MAGIC {
+            return Err(ManifestError::Magic);
+        }
+        match u16_at(4) {
+            MANIFEST_VERSION => {}
+            version => return Err(ManifestError::Version(version)),
+        }
+        if u32_at(8) != crate::crc32(&bytes[CRC_END..]) {
+            return Err(ManifestError::Crc);
+        }
+
+        let image_type = match u16_at(16) {
+            1 => ImageType::Application,
+            image_type => return Err(ManifestError::ImageType(image_type)),
+        };
+        let hash_algorithm = match bytes[28] {
+            1 => HashAlgorithm::Sha1,
+            2 => HashAlgorithm::Sha256,
+            algorithm => return Err(ManifestError::HashAlgorithm(algorithm)),
+        };
+        let mut hash = [0; MAX_HASH_LENGTH];
+        hash.copy_from_slice(&bytes[32..]);
+
+        Ok(Self {
+            image_length: u32_at(12),
+            image_type,
+            flags: u16_at(18),
+            load_address: u32_at(20),
+            security_version: u32_at(24),
+            hash_algorithm,
+            hash,
+        })
+    }
+}
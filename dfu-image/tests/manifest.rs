@@ -0,0 +1,72 @@
+use dfu_image::*;
+
+fn manifest() -> Manifest {
+    Manifest::new(
+        ImageType::Application,
+        0x0800_8000,
+        0x1234,
+        2,
+        HashAlgorithm::Sha1,
+        &[0xA5; 20],
+    )
+}
+
+#[test]
+fn fields_are_little_endian_at_fixed_offsets() {
+    let bytes = manifest().to_bytes();
+    assert_eq!(&bytes[0..4], b"DMAN");
+    assert_eq!(&bytes[4..6], &[1, 0]);
+    assert_eq!(&bytes[12..16], &[0x34, 0x12, 0, 0]);
+    assert_eq!(&bytes[16..18], &[1, 0]);
+    assert_eq!(&bytes[20..24], &[0x00, 0x80, 0x00, 0x08]);
+    assert_eq!(&bytes[24..28], &[2, 0, 0, 0]);
+    assert_eq!(bytes[28], 1);
+    assert_eq!(&bytes[32..52], &[0xA5; 20]);
+    assert_eq!(&bytes[52..], &[0; 12]);
+    assert_eq!(&bytes[8..12], &crc32(&bytes[12..]).to_le_bytes());
+}
+
+#[test]
+fn round_trip() {
+    let mut manifest = manifest();
+    manifest.flags = FLAG_SIGNED;
+    assert_eq!(
+        Manifest::from_bytes(&manifest.to_bytes()),
+        Ok(manifest.clone())
+    );
+    assert_eq!(manifest.hash(), &[0xA5; 20]);
+    assert_eq!(
+        manifest.encoded_length(),
+        MANIFEST_LENGTH + SIGNATURE_LENGTH
+    );
+}
+
+#[test]
+fn unknown_or_damaged_manifests_are_rejected() {
+    let bytes = manifest().to_bytes();
+    assert_eq!(
+        Manifest::from_bytes(&[0xFF; MANIFEST_LENGTH]),
+        Err(ManifestError::Magic)
+    );
+
+    let mut newer = bytes;
+    newer[4] = 2;
+    assert_eq!(Manifest::from_bytes(&newer), Err(ManifestError::Version(2)));
+
+    let mut damaged = bytes;
+    damaged[40] ^= 1;
+    assert_eq!(Manifest::from_bytes(&damaged), Err(ManifestError::Crc));
+}
+
+#[test]
+fn security_version_comes_from_the_image_header() {
+    let mut image = vec![0; IMAGE_HEADER_OFFSET + 8];
+    assert_eq!(ImageHeader::security_version_of(&image), 0);
+
+    image[IMAGE_HEADER_OFFSET..].copy_from_slice(&[b'I', b'M', b'D', b'H', 3, 0, 0, 0]);
+    assert_eq!(ImageHeader::security_version_of(&image), 3);
+    assert_eq!(
+        ImageHeader::security_version_of(&image[..IMAGE_HEADER_OFFSET + 4]),
+        0
+    );
+}
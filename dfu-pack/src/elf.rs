@@ -0,0 +1,161 @@
+//! Just enough of ELF to lay out the loadable segments of a 32 bits little endian executable.
+
+use std::error::Error;
+use std::ops::Range;
+
+const PT_LOAD: u32 = 1;
+
+pub fn is_elf(file: &[u8]) -> bool {
+    file.starts_with(b"\x7fELF")
+}
+
+/// The segments' content placed at their load address, gaps are filled with 0xFF as erased flash.
+/// Every segment must lie in `region`, which bounds the image. Returns the address of the first
+/// byte and the image.
+pub fn flatten(file: &[u8], region: Range<u32>) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+    // EI_CLASS: 32 bits, EI_DATA: little endian
+    if file.get(4..6) != Some(&[1, 1]) {
+        return Err("only 32 bits little endian ELF files are supported".into());
+    }
+    let bytes_at = |offset: usize, len: usize| -> Result<&[u8], Box<dyn Error>> {
+        let end = offset.checked_add(len).ok_or("truncated ELF file")?;
+        Ok(file.get(offset..end).ok_or("truncated ELF file")?)
+    };
+    let u16_at = |offset: usize| -> Result<u16, Box<dyn Error>> {
+        let bytes = bytes_at(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let u32_at = |offset: usize| -> Result<u32, Box<dyn Error>> {
+        let bytes = bytes_at(offset, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let ph_offset = u32_at(0x1C)? as usize;
+    let ph_size = usize::from(u16_at(0x2A)?);
+    let ph_count = usize::from(u16_at(0x2C)?);
+
+    let mut segments = Vec::new();
+    for i in 0..ph_count {
+        let header = i
+            .checked_mul(ph_size)
+            .and_then(|offset| offset.checked_add(ph_offset))
+            .ok_or("truncated ELF file")?;
+        // the fields read below can't overflow once the header is known to be in the file
+        bytes_at(header, 0x14)?;
+        let file_size = u32_at(header + 0x10)? as usize;
+        if u32_at(header)? != PT_LOAD || file_size == 0 {
+            continue;
+        }
+        let offset = u32_at(header + 0x04)? as usize;
+        // what ends up in flash is found at the physical address
+        let address = u32_at(header + 0x0C)?;
+        let end = u64::from(address) + file_size as u64;
+        if address < region.start || end > u64::from(region.end) {
+            return Err(format!(
+                "a segment at {:#010x}..{:#010x} is out of the region {:#010x}..{:#010x}",
+                address, end, region.start, region.end
+            )
+            .into());
+        }
+        let data = bytes_at(offset, file_size).map_err(|_| "segment out of the ELF file")?;
+        segments.push((address, data));
+    }
+
+    let start = segments
+        .iter()
+        .map(|(address, _)| *address)
+        .min()
+        .ok_or("no loadable segment")?;
+    let end = segments
+        .iter()
+        .map(|(address, data)| *address as usize + data.len())
+        .max()
+        .unwrap_or(start as usize);
+
+    // bounded by the region
+    let mut image = vec![0xFF; end - start as usize];
+    for (address, data) in segments {
+        let offset = (address - start) as usize;
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok((start, image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION: Range<u32> = 0x0800_8000..0x0808_0000;
+
+    /// An ELF header followed by one program header per segment, then the segments' content.
+    fn elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut file = vec![0; 0x34];
+        file[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        file[0x1C..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        file[0x2A..0x2C].copy_from_slice(&0x20u16.to_le_bytes());
+        file[0x2C..0x2E].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = 0x34 + 0x20 * segments.len();
+        for (kind, address, data) in segments {
+            let mut header = [0; 0x20];
+            header[..4].copy_from_slice(&kind.to_le_bytes());
+            header[0x04..0x08].copy_from_slice(&(offset as u32).to_le_bytes());
+            header[0x0C..0x10].copy_from_slice(&address.to_le_bytes());
+            header[0x10..0x14].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            file.extend_from_slice(&header);
+            offset += data.len();
+        }
+        for (_, _, data) in segments {
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    #[test]
+    fn segments_are_laid_out_at_their_physical_address() {
+        let file = elf(&[
+            (PT_LOAD, 0x0800_8010, &[3, 4]),
+            (PT_LOAD, 0x0800_8000, &[1, 2]),
+            // not loaded
+            (6, 0x0800_8020, &[5]),
+        ]);
+        let (start, image) = flatten(&file, REGION).unwrap();
+        assert_eq!(start, 0x0800_8000);
+        let mut expected = vec![0xFF; 0x12];
+        expected[..2].copy_from_slice(&[1, 2]);
+        expected[0x10..].copy_from_slice(&[3, 4]);
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn segments_out_of_the_region_are_refused() {
+        let file = elf(&[(PT_LOAD, 0x0800_8000, &[1]), (PT_LOAD, 0x2000_0000, &[2])]);
+        assert!(flatten(&file, REGION).is_err());
+        let file = elf(&[(PT_LOAD, REGION.end - 1, &[1, 2])]);
+        assert!(flatten(&file, REGION).is_err());
+        let file = elf(&[(PT_LOAD, u32::MAX, &[1, 2])]);
+        assert!(flatten(&file, REGION).is_err());
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        let file = elf(&[(PT_LOAD, 0x0800_8000, &[1, 2, 3, 4])]);
+        assert!(flatten(&file[..file.len() - 1], REGION).is_err());
+        assert!(flatten(&file[..0x40], REGION).is_err());
+
+        let mut file = elf(&[(PT_LOAD, 0x0800_8000, &[1])]);
+        file[0x1C..0x20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(flatten(&file, REGION).is_err());
+        let mut file = elf(&[(PT_LOAD, 0x0800_8000, &[1])]);
+        file[0x34 + 0x04..0x34 + 0x08].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(flatten(&file, REGION).is_err());
+    }
+
+    #[test]
+    fn only_32_bits_little_endian_files_are_supported() {
+        let mut file = elf(&[(PT_LOAD, 0x0800_8000, &[1])]);
+        file[4] = 2;
+        assert!(flatten(&file, REGION).is_err());
+        assert!(flatten(b"\x7fELF", REGION).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Turns an application, ELF or raw binary, into a `.dfu` file the bootloader accepts: the image is
+//! checked against the application region, then prefixed by its manifest and suffixed as DFU 1.1
+//! requires.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use dfu_image::{
+    crc32, HashAlgorithm, ImageHeader, ImageType, Manifest, FLAG_SIGNED, MANIFEST_LENGTH,
+};
+
+mod elf;
+
+const USAGE: &str = "\
+usage: dfu-pack [options] <input> <output.dfu>
+
+<input> is an ELF file or a raw binary.
+
+options:
+  --address <address>  start of the application region [default: 0x0800_8000]
+  --length <bytes>     size of the application region [default: 0x7_7F80]
+  --sha256             hash the image with SHA-256 instead of SHA-1
+  --key <file>         sign the manifest with the HMAC-SHA256 key read from <file>, the
+                       bootloader does not check signatures yet
+  --vid <id>           vendor id written to the DFU suffix [default: 0x16c0]
+  --pid <id>           product id written to the DFU suffix [default: 0x27dd]";
+
+/// Defaults match the nucleo-f401re, see `nucleo-f401re-application.x`.
+struct Options {
+    address: u32,
+    length: u32,
+    hash_algorithm: HashAlgorithm,
+    key: Option<Vec<u8>>,
+    vid: u16,
+    pid: u16,
+    input: String,
+    output: String,
+}
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut address = 0x0800_8000;
+        let mut length = 0x0007_7F80;
+        let mut hash_algorithm = HashAlgorithm::Sha1;
+        let mut key = None;
+        let mut vid = 0x16c0;
+        let mut pid = 0x27dd;
+        let mut paths = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--address" => address = parse_number(&value()?)?,
+                "--length" => length = parse_number(&value()?)?,
+                "--sha256" => hash_algorithm = HashAlgorithm::Sha256,
+                "--key" => key = Some(std::fs::read(value()?)?),
+                "--vid" => vid = parse_number(&value()?)? as u16,
+                "--pid" => pid = parse_number(&value()?)? as u16,
+                "-h" | "--help" => return Err(USAGE.into()),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg).into()),
+                _ => paths.push(arg),
+            }
+        }
+
+        match <[String; 2]>::try_from(paths) {
+            Ok([input, output]) => Ok(Self {
+                address,
+                length,
+                hash_algorithm,
+                key,
+                vid,
+                pid,
+                input,
+                output,
+            }),
+            Err(_) => Err(USAGE.into()),
+        }
+    }
+}
+
+/// Decimal or, with a `0x` prefix, hexadecimal. Underscores are ignored.
+fn parse_number(value: &str) -> Result<u32, Box<dyn Error>> {
+    let value = value.replace('_', "");
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    number.map_err(|e| format!("{}: {}", value, e).into())
+}
+
+/// Checks that the image fits the application region and that it starts with a vector table
+/// pointing into it.
+fn check(options: &Options, address: u32, image: &[u8]) -> Result<(), Box<dyn Error>> {
+    if address != options.address {
+        return Err(format!(
+            "the image starts at {:#010x}, the application region at {:#010x}",
+            address, options.address
+        )
+        .into());
+    }
+    if image.len() > options.length as usize {
+        return Err(format!(
+            "the image takes {} bytes, the application region only has {}",
+            image.len(),
+            options.length
+        )
+        .into());
+    }
+    if image.len() < 8 {
+        return Err("the image is too short to hold a vector table".into());
+    }
+
+    let reset = u32::from_le_bytes([image[4], image[5], image[6], image[7]]);
+    let region = u64::from(address)..u64::from(address) + image.len() as u64;
+    // thumb code, the lowest bit is set
+    if reset & 1 == 0 || !region.contains(&u64::from(reset & !1)) {
+        return Err(format!("the reset vector {:#010x} is not in the image", reset).into());
+    }
+    Ok(())
+}
+
+fn hash(algorithm: HashAlgorithm, image: &[u8]) -> Vec<u8> {
+    match algorithm {
+        HashAlgorithm::Sha1 => {
+            let mut sha = sha1::Sha1::new();
+            sha.update(image);
+            sha.digest().bytes().to_vec()
+        }
+        HashAlgorithm::Sha256 => hmac_sha256::Hash::hash(image).to_vec(),
+    }
+}
+
+/// DFU 1.1 suffix: bcdDevice, idProduct, idVendor, bcdDFU, "UFD", bLength and the CRC of the whole
+/// file. The CRC is the CRC-32 left uninverted, as dfu-util computes it.
+fn append_suffix(file: &mut Vec<u8>, vid: u16, pid: u16) {
+    file.extend_from_slice(&0xFFFFu16.to_le_bytes());
+    file.extend_from_slice(&pid.to_le_bytes());
+    file.extend_from_slice(&vid.to_le_bytes());
+    file.extend_from_slice(&0x0100u16.to_le_bytes());
+    file.extend_from_slice(b"UFD");
+    file.push(16);
+    let crc = !crc32(file);
+    file.extend_from_slice(&crc.to_le_bytes());
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let options = Options::parse(std::env::args().skip(1))?;
+
+    let input = std::fs::read(&options.input)?;
+    let (address, image) = if elf::is_elf(&input) {
+        let end = options
+            .address
+            .checked_add(options.length)
+            .ok_or("the application region ends past 4 GiB")?;
+        elf::flatten(&input, options.address..end)?
+    } else {
+        (options.address, input)
+    };
+    check(&options, address, &image)?;
+
+    let mut manifest = Manifest::new(
+        ImageType::Application,
+        address,
+        image.len() as u32,
+        ImageHeader::security_version_of(&image),
+        options.hash_algorithm,
+        &hash(options.hash_algorithm, &image),
+    );
+    if options.key.is_some() {
+        manifest.flags |= FLAG_SIGNED;
+    }
+    let manifest_bytes = manifest.to_bytes();
+
+    let mut file = Vec::with_capacity(MANIFEST_LENGTH + image.len() + 64);
+    file.extend_from_slice(&manifest_bytes);
+    if let Some(key) = &options.key {
+        file.extend_from_slice(&hmac_sha256::HMAC::mac(&manifest_bytes[..], &key[..]));
+    }
+    file.extend_from_slice(&image);
+    append_suffix(&mut file, options.vid, options.pid);
+
+    std::fs::write(&options.output, &file)?;
+    println!(
+        "{}: {} bytes at {:#010x}, security version {}{}",
+        options.output,
+        image.len(),
+        address,
+        manifest.security_version,
+        if options.key.is_some() {
+            ", signed (not checked by the bootloader yet)"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        let args = ["in.elf", "out.dfu"].iter().map(|arg| arg.to_string());
+        Options::parse(args).unwrap()
+    }
+
+    /// A vector table whose reset vector points `reset` bytes into the image.
+    fn image(len: usize, reset: u32) -> Vec<u8> {
+        let mut image = vec![0; len];
+        image[4..8].copy_from_slice(&(0x0800_8000 + reset + 1).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn numbers_are_decimal_or_hexadecimal() {
+        assert_eq!(parse_number("1234").unwrap(), 1234);
+        assert_eq!(parse_number("0x0800_8000").unwrap(), 0x0800_8000);
+        assert_eq!(parse_number("1_000").unwrap(), 1000);
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12a").is_err());
+        assert!(parse_number("0x1_0000_0000").is_err());
+    }
+
+    #[test]
+    fn images_must_fit_the_application_region() {
+        let options = options();
+        assert!(check(&options, 0x0800_8000, &image(64, 8)).is_ok());
+        assert!(check(&options, 0x0800_0000, &image(64, 8)).is_err());
+        let too_long = image(options.length as usize + 1, 8);
+        assert!(check(&options, 0x0800_8000, &too_long).is_err());
+        assert!(check(&options, 0x0800_8000, &[0; 7]).is_err());
+    }
+
+    #[test]
+    fn reset_vector_must_point_to_thumb_code_in_the_image() {
+        let options = options();
+        assert!(check(&options, 0x0800_8000, &image(64, 64)).is_err());
+        let mut arm = image(64, 8);
+        arm[4] &= !1;
+        assert!(check(&options, 0x0800_8000, &arm).is_err());
+    }
+
+    #[test]
+    fn suffix_ends_with_the_crc_of_the_file() {
+        let mut file = vec![1, 2, 3];
+        append_suffix(&mut file, 0x16c0, 0x27dd);
+        assert_eq!(file.len(), 19);
+        #[rustfmt::skip]
+        assert_eq!(&file[..15], &[
+            1, 2, 3,
+            0xFF, 0xFF, 0xdd, 0x27, 0xc0, 0x16, 0x00, 0x01, b'U', b'F', b'D', 16,
+        ][..]);
+        let crc = u32::from_le_bytes([file[15], file[16], file[17], file[18]]);
+        assert_eq!(crc, !crc32(&file[..15]));
+    }
+}
@@ -1,5 +1,16 @@
 MEMORY
 {
   FLASH (rx) : ORIGIN = 0x400000, LENGTH = 32K
-  RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K
+  RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K - 1K
+  CRASH (rwx) : ORIGIN = 0x20000000 + 128K - 1K, LENGTH = 1K
+}
+
+/* The crash record is shared by the bootloader and the application, it has to be at the same
+   address in both and out of reach of their startup code. */
+SECTIONS
+{
+  .crash_record (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.crash_record));
+  } > CRASH
 }
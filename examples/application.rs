@@ -3,8 +3,11 @@
 #![feature(maybe_uninit_ref)]
 #![feature(alloc_error_handler)]
 
+use core::cell::{Cell, RefCell};
+use pin_utils::pin_mut;
+
 use usbd_dfu_demo::dbgprint;
-use usbd_dfu_demo::executor;
+use usbd_dfu_demo::executor::{self, Timer};
 use usbd_dfu_demo::platform;
 
 use alloc_cortex_m::CortexMHeap;
@@ -12,7 +15,16 @@ use embedded_hal::digital::v2::OutputPin;
 
 use usb_device::prelude::*;
 use usbd_dfu::runtime::DFURuntimeClass;
-use usbd_serial::{SerialPort, /* CDC_SUBCLASS_ACM,*/ USB_CLASS_CDC};
+use usbd_dfu::Instant;
+use usbd_serial::SerialPort;
+
+/// Time, in milliseconds, the application must stay configured by the host before its image is
+/// confirmed. It is longer than the watchdog timeout so an image that keeps resetting is not.
+const HEALTHY_AFTER_MS: u32 = 5000;
+
+/// Longest time, in milliseconds, debug output waits before being forwarded to the serial port.
+#[cfg(feature = "debug-cdc")]
+const DEBUG_FLUSH_MS: u32 = 10;
 
 #[global_allocator]
 static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
@@ -29,7 +41,7 @@ fn oom(layout: core::alloc::Layout) -> ! {
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    let (usb_bus, mut led, mut cp, dfu) = platform::init();
+    let (usb_bus, mut led, _cp, dfu) = platform::init();
 
     // Initialize the allocator BEFORE you use it
     let start = cortex_m_rt::heap_start() as usize;
@@ -37,38 +49,34 @@ fn main() -> ! {
     unsafe { ALLOCATOR.init(start, size) };
 
     let mut serial = SerialPort::new_with_store(&usb_bus, [0u8; 128], [0u8; 1024]);
-    let mut dfu = DFURuntimeClass::new(&usb_bus, dfu);
+    let dfu = RefCell::new(DFURuntimeClass::new(&usb_bus, dfu, || {
+        Instant::from_millis(executor::now())
+    }));
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .manufacturer("Fake company")
         .product("Serial port")
         .serial_number("TEST")
         .max_packet_size_0(64)
-        .device_class(USB_CLASS_CDC)
-        //.device_sub_class(CDC_SUBCLASS_ACM)
-        .device_sub_class(2)
-        //.device_protocol(CDC_PROTOCOL_NONE)
-        .device_protocol(0)
+        // the serial port and the DFU interface are each described by an association
+        .composite_with_iads()
         .build();
 
-    let mut buf = [0u8; 256];
-    #[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-    let mut timestamp = 0u64;
-    #[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-    let mut trace_printed = false;
+    let configured = Cell::new(false);
 
-    executor::block_on(async move {
+    // Checks the DFU detach timeout.
+    let dfu_timing = async {
         loop {
-            if cp.SYST.has_wrapped() {
-                dfu.poll(1);
-                #[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-                if usb_dev.state() == UsbDeviceState::Configured {
-                    timestamp += 1;
-                } else {
-                    timestamp = 0;
-                }
-            }
+            Timer::after(1).await;
+            platform::watchdog::feed();
+            dfu.borrow_mut().poll();
+        }
+    };
 
-            usb_dev.poll(&mut [&mut serial, &mut dfu]);
+    let usb = async {
+        let mut buf = [0u8; 256];
+        loop {
+            usb_dev.poll(&mut [&mut serial, &mut *dfu.borrow_mut()]);
+            configured.set(usb_dev.state() == UsbDeviceState::Configured);
 
             let mut _count = match serial.read(&mut buf) {
                 Ok(count) => {
@@ -85,44 +93,15 @@ fn main() -> ! {
                 _ => 0,
             };
 
-            if let [0x0D, ..] = buf {
-                // read flash desc
-                let _v = dfu.handler().read_manifest().await;
-                dbgprint!("{:?}", _v);
-            }
-
-            // transfers previous error to trace buffer
-            #[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-            if timestamp >= 5000 && usb_dev.state() == UsbDeviceState::Configured && !trace_printed
-            {
-                unsafe {
-                    let err = usbd_dfu_demo::trace::ERROR.assume_init_mut();
-                    if err.len > 0 && err.len < err.buffer.len() {
-                        dbgprint!(
-                            "{} \"{}\"\r\n",
-                            err.len,
-                            core::str::from_utf8_unchecked(&err.buffer[..err.len])
-                        );
-                    } else {
-                        dbgprint!("All clear, you're good to go.\r\n");
-                    }
-                    err.len = 0;
-                };
-                trace_printed = true;
-            }
-
-            #[cfg(feature = "debug-buffer")]
+            #[cfg(feature = "debug-cdc")]
             {
                 // transfers trace buffer to output buffer
-                platform::debug::consume_debug(|dbg| {
+                usbd_dfu_demo::debug::consume(|dbg| {
                     let len = core::cmp::min(dbg.len(), buf.len() - _count);
                     buf[_count.._count + len].copy_from_slice(&dbg[..len]);
                     _count += len;
                     len
                 });
-                if _count == 0 {
-                    continue;
-                }
 
                 // transfers trace buffer to output buffer
                 let mut wr_ptr = &buf[.._count];
@@ -134,7 +113,86 @@ fn main() -> ! {
             }
 
             let _ = led.set_high(); // Turn off
+
+            #[cfg(feature = "debug-cdc")]
+            {
+                // the other tasks' output is forwarded without waiting for the host
+                let event = platform::wait_for_event();
+                let flush = Timer::after(DEBUG_FLUSH_MS);
+                pin_mut!(event, flush);
+                futures::future::select(event, flush).await;
+            }
+            #[cfg(not(feature = "debug-cdc"))]
+            platform::wait_for_event().await;
+        }
+    };
+
+    // Placeholder health check: the host can reach the DFU interface, so the bootloader doesn't
+    // need to step in. A real application confirms its image once it has checked what it is
+    // there for, e.g. its sensors or its peers.
+    let health = async {
+        let mut configured_ms = 0;
+        while configured_ms < HEALTHY_AFTER_MS {
+            Timer::after(100).await;
+            configured_ms = if configured.get() {
+                configured_ms + 100
+            } else {
+                0
+            };
+        }
+        platform::boot_attempts::clear();
+    };
+
+    // transfers previous error to trace buffer once the host had 5s to open the port
+    #[cfg(feature = "debug")]
+    let trace = async {
+        loop {
+            while !configured.get() {
+                Timer::after(100).await;
+            }
+            Timer::after(5000).await;
+            if configured.get() {
+                break;
+            }
+        }
+
+        use usbd_dfu_demo::trace;
+        if let Some(record) = trace::last_crash() {
+            dbgprint!(
+                "{} crash(es), last one: \"{}\" at {}:{}:{}",
+                record.reset_count,
+                record.message(),
+                record.file(),
+                record.line,
+                record.column
+            );
+            if let Some(fault) = record.fault() {
+                dbgprint!(
+                    "pc: {:#x} lr: {:#x} cfsr: {:#x} hfsr: {:#x} mmfar: {:#x} bfar: {:#x}",
+                    fault.frame.pc(),
+                    fault.frame.lr(),
+                    fault.cfsr,
+                    fault.hfsr,
+                    fault.mmfar,
+                    fault.bfar
+                );
+            }
+            dbgprint!("sp: {:#x}", record.sp);
+            for word in record.stack() {
+                dbgprint!("  {:#x}", word);
+            }
+        } else {
+            dbgprint!("All clear, you're good to go.");
         }
-    });
-    unreachable!();
+        trace::clear();
+    };
+
+    pin_mut!(dfu_timing, usb, health);
+    #[cfg(feature = "debug")]
+    {
+        pin_mut!(trace);
+        executor::run(&mut [dfu_timing, usb, health, trace])
+    }
+    #[cfg(not(feature = "debug"))]
+    executor::run(&mut [dfu_timing, usb, health])
 }
@@ -2,14 +2,22 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 
+use core::cell::RefCell;
 use embedded_hal::digital::v2::ToggleableOutputPin;
+use pin_utils::pin_mut;
 
 use alloc_cortex_m::CortexMHeap;
 
+use usbd_dfu_demo::executor::{self, Timer};
 use usbd_dfu_demo::platform;
 
 use usb_device::prelude::*;
 use usbd_dfu::mode::DFUModeClass;
+use usbd_dfu::Instant;
+
+/// Time, in milliseconds, the bootloader waits for a DFU request before falling back to a valid
+/// application.
+const INACTIVITY_TIMEOUT_MS: u32 = 30_000;
 
 #[global_allocator]
 static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
@@ -34,15 +42,19 @@ fn main() -> ! {
         unsafe { ALLOCATOR.init(start, size) };
     }
 
-    let (usb_bus, mut led, mut cp, mut dfu) = platform::init();
+    let (usb_bus, mut led, _cp, mut dfu) = platform::init();
 
     use usbd_dfu::mode::DeviceFirmwareUpgrade;
     if dfu.is_firmware_valid() {
         usbd_dfu_demo::dbgprint!("Firmware is valid");
+        // the application may have confirmed its image on its last run
+        let _ = executor::block_on(dfu.commit_security_version());
         platform::jump_to_application();
     }
 
-    let mut dfu = DFUModeClass::new(&usb_bus, dfu);
+    let dfu = RefCell::new(DFUModeClass::new(&usb_bus, dfu, || {
+        Instant::from_millis(executor::now())
+    }));
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .manufacturer("Fake company")
         .product("Serial port")
@@ -55,17 +67,52 @@ fn main() -> ! {
         .device_protocol(0)
         .build();
 
-    let mut counter: usize = 0;
-    loop {
-        dfu.poll(0);
-        if cp.SYST.has_wrapped() {
-            dfu.poll(1);
+    // Keeps the flash operations going and the DFU timeouts checked.
+    let dfu_timing = async {
+        let mut counter: usize = 0;
+        loop {
+            Timer::after(1).await;
+            platform::watchdog::feed();
+            dfu.borrow_mut().poll();
             counter = counter.wrapping_add(1);
             if counter % 1000 == 0 {
                 let _ = led.toggle();
             }
         }
+    };
 
-        usb_dev.poll(&mut [&mut dfu]);
-    }
+    // Carries on with a download as soon as an erase ends, rather than on the next tick.
+    let flash = async {
+        loop {
+            platform::erase_ended().await;
+            dfu.borrow_mut().poll();
+        }
+    };
+
+    let usb = async {
+        loop {
+            let mut dfu = dfu.borrow_mut();
+            dfu.poll();
+            usb_dev.poll(&mut [&mut *dfu]);
+            drop(dfu);
+
+            platform::wait_for_event().await;
+        }
+    };
+
+    // Starts the application if the host did not talk to the bootloader in time, whether USB got
+    // configured or not.
+    let inactivity = async {
+        Timer::after(INACTIVITY_TIMEOUT_MS).await;
+
+        let mut dfu = dfu.borrow_mut();
+        if !dfu.has_activity() && dfu.handler().is_firmware_valid() {
+            usbd_dfu_demo::dbgprint!("No DFU activity, starting the application");
+            let _ = executor::block_on(dfu.handler().commit_security_version());
+            platform::jump_to_application();
+        }
+    };
+
+    pin_mut!(dfu_timing, flash, usb, inactivity);
+    executor::run(&mut [dfu_timing, flash, usb, inactivity])
 }
@@ -2,10 +2,32 @@ MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   FLASH : ORIGIN = 0x08008000, LENGTH = 512K - 32K
-  RAM : ORIGIN = 0x20000000, LENGTH = 96K
+  RAM : ORIGIN = 0x20000000, LENGTH = 96K - 1K
+  CRASH : ORIGIN = 0x20000000 + 96K - 1K, LENGTH = 1K
 }
 
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
+
+/* The crash record is shared by the bootloader and the application, it has to be at the same
+   address in both and out of reach of their startup code. */
+SECTIONS
+{
+  .crash_record (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.crash_record));
+  } > CRASH
+}
+
+/* The image header follows the vector table, at the offset the bootloader reads it from. The
+   vector table of the STM32F401 takes 0x194 bytes. */
+SECTIONS
+{
+  .image_header ORIGIN(FLASH) + 0x200 :
+  {
+    KEEP(*(.image_header));
+  } > FLASH
+} INSERT AFTER .vector_table;
+_stext = ADDR(.image_header) + SIZEOF(.image_header);
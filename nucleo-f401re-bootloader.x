@@ -2,10 +2,50 @@ MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   FLASH : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM : ORIGIN = 0x20000000, LENGTH = 96K
+  RAM : ORIGIN = 0x20000000, LENGTH = 96K - 1K
+  CRASH : ORIGIN = 0x20000000 + 96K - 1K, LENGTH = 1K
 }
 
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
+
+/* The crash record is shared by the bootloader and the application, it has to be at the same
+   address in both and out of reach of their startup code. */
+SECTIONS
+{
+  .crash_record (NOLOAD) : ALIGN(4)
+  {
+    KEEP(*(.crash_record));
+  } > CRASH
+}
+
+/* Flash can't be read while a sector is being erased, so the bootloader runs from RAM and keeps
+   servicing USB meanwhile. Its code and read-only data are linked to RAM with their load address
+   in FLASH, right after the vector table, of which a copy is kept in RAM too. `load_ram_text`
+   copies both before the runtime initialises RAM. Only the reset and HardFault handlers are left to
+   run from FLASH. */
+SECTIONS
+{
+  .ramvectors (NOLOAD) : ALIGN(512)
+  {
+    __sramvectors = .;
+    . += SIZEOF(.vector_table);
+    __eramvectors = .;
+  } > RAM
+
+  .ramtext : ALIGN(4)
+  {
+    __sramtext = .;
+    *(.text .text.*);
+    *(.rodata .rodata.*);
+    . = ALIGN(4);
+    __eramtext = .;
+  } > RAM AT> FLASH
+  __siramtext = LOADADDR(.ramtext);
+  __svectors = ADDR(.vector_table);
+} INSERT BEFORE .text;
+
+/* what is left of `.text` follows the load image of `.ramtext` */
+_stext = __siramtext + SIZEOF(.ramtext);
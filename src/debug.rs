@@ -0,0 +1,303 @@
+//! Debug output shared by every platform.
+//!
+//! The platform's `init` installs one `DebugSink`, picked by the `debug-*` features:
+//! - `debug-uart`: written synchronously to a UART.
+//! - `debug-buffer`: kept in a RAM ring buffer, read through `consume` or the DFU diagnostics.
+//! - `debug-cdc`: kept in a RAM ring buffer that the application forwards to its CDC port.
+//! - `debug-rtt`: written to a SEGGER RTT up channel, read by the debug probe.
+//!
+//! `dbgprint!` writes one line to the sink and `consume` drains what it buffered. With `use-defmt`,
+//! `dbgprint!` logs through defmt instead, the sink then only carries defmt frames.
+
+use core::fmt;
+
+pub trait DebugSink {
+    fn write_bytes(&mut self, bytes: &[u8]);
+
+    /// Writes a whole defmt frame, or drops it when there is no room: a cut frame garbles the
+    /// frames around it. Sinks that never drop output write it like any other bytes.
+    fn write_frame(&mut self, frame: &[u8]) {
+        self.write_bytes(frame);
+    }
+
+    /// Output not consumed yet, oldest first. Sinks that don't buffer anything have none.
+    fn pending(&self) -> [&[u8]; 2] {
+        [&[], &[]]
+    }
+
+    /// Drops the `len` oldest bytes of the pending output.
+    fn skip(&mut self, len: usize) {
+        let _ = len;
+    }
+}
+
+static mut SINK: Option<&'static mut dyn DebugSink> = None;
+
+/// Installs the sink `dbgprint!` writes to. Called once by the platform's `init`.
+pub fn install(sink: &'static mut dyn DebugSink) {
+    cortex_m::interrupt::free(move |_| unsafe { SINK = Some(sink) });
+}
+
+/// Installs the RAM backed sink selected by `debug-buffer`, `debug-cdc` or `debug-rtt`, using
+/// `buffer` as its storage.
+pub fn install_buffered(buffer: &'static mut [u8]) {
+    #[cfg(feature = "debug-rtt")]
+    {
+        static mut RTT: Option<Rtt> = None;
+        install(unsafe { RTT.get_or_insert(Rtt::new(buffer)) });
+    }
+    #[cfg(not(feature = "debug-rtt"))]
+    {
+        static mut RING: Option<RingBuffer> = None;
+        install(unsafe { RING.get_or_insert(RingBuffer::new(buffer)) });
+    }
+}
+
+fn with_sink<R>(f: impl FnOnce(&mut dyn DebugSink) -> R) -> Option<R> {
+    cortex_m::interrupt::free(|_| unsafe { SINK.as_mut().map(|sink| f(&mut **sink)) })
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    with_sink(|sink| sink.write_bytes(bytes));
+}
+
+pub fn write_frame(frame: &[u8]) {
+    with_sink(|sink| sink.write_frame(frame));
+}
+
+pub fn write_fmt(args: fmt::Arguments) {
+    struct Writer<'a>(&'a mut dyn DebugSink);
+    impl fmt::Write for Writer<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.write_bytes(s.as_bytes());
+            Ok(())
+        }
+    }
+    with_sink(|sink| fmt::Write::write_fmt(&mut Writer(sink), args));
+}
+
+/// Hands the pending output to `reader`, which returns how many bytes it took.
+pub fn consume(mut reader: impl FnMut(&[u8]) -> usize) {
+    with_sink(|sink| loop {
+        let [first, _] = sink.pending();
+        if first.is_empty() {
+            break;
+        }
+        let len = first.len();
+        let taken = core::cmp::min(reader(first), len);
+        sink.skip(taken);
+        if taken < len {
+            break;
+        }
+    });
+}
+
+/// Gives access to the pending output without consuming it.
+pub fn with_pending<R>(f: impl FnOnce([&[u8]; 2]) -> R) -> R {
+    cortex_m::interrupt::free(|_| unsafe {
+        match SINK.as_ref() {
+            Some(sink) => f(sink.pending()),
+            None => f([&[], &[]]),
+        }
+    })
+}
+
+/// Writes a line. Its format string must also suit defmt: `{}`, `{:?}` and the `x` hints only.
+#[macro_export]
+#[cfg(all(feature = "debug", not(feature = "use-defmt")))]
+macro_rules! dbgprint {
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::debug::write_fmt(format_args!(concat!($fmt, "\r\n") $($arg)*))
+    };
+}
+
+#[macro_export]
+#[cfg(all(feature = "debug", feature = "use-defmt"))]
+macro_rules! dbgprint {
+    ($($arg:tt)*) => {
+        defmt::println!($($arg)*)
+    };
+}
+
+#[macro_export]
+#[cfg(not(feature = "debug"))]
+macro_rules! dbgprint {
+    ($($arg:tt)*) => {};
+}
+
+// ================================================================================================
+// backends
+
+/// Blocks until every byte has been handed to the UART.
+pub struct Uart<W>(pub W);
+impl<W: embedded_hal::serial::Write<u8>> DebugSink for Uart<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            while self.0.write(*byte).is_err() {}
+        }
+    }
+}
+
+/// Keeps the most recent output, the oldest bytes are dropped when it is full. Frames make room by
+/// dropping the oldest frames whole, each ends with the zero byte of its rzCOBS encoding.
+pub struct RingBuffer {
+    buffer: &'static mut [u8],
+    start: usize,
+    len: usize,
+}
+impl RingBuffer {
+    pub fn new(buffer: &'static mut [u8]) -> Self {
+        Self {
+            buffer,
+            start: 0,
+            len: 0,
+        }
+    }
+}
+impl DebugSink for RingBuffer {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let capacity = self.buffer.len();
+        if capacity == 0 {
+            return;
+        }
+        for byte in bytes {
+            if self.len == capacity {
+                self.start = (self.start + 1) % capacity;
+                self.len -= 1;
+            }
+            self.buffer[(self.start + self.len) % capacity] = *byte;
+            self.len += 1;
+        }
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) {
+        let capacity = self.buffer.len();
+        if frame.len() > capacity {
+            return;
+        }
+        while capacity - self.len < frame.len() {
+            let [first, second] = self.pending();
+            let frame_end = match first.iter().chain(second).position(|byte| *byte == 0) {
+                Some(position) => position + 1,
+                None => self.len,
+            };
+            self.skip(frame_end);
+        }
+        self.write_bytes(frame);
+    }
+
+    fn pending(&self) -> [&[u8]; 2] {
+        let end = self.start + self.len;
+        if end <= self.buffer.len() {
+            [&self.buffer[self.start..end], &[]]
+        } else {
+            let (wrapped, tail) = self.buffer.split_at(self.start);
+            [tail, &wrapped[..end - self.buffer.len()]]
+        }
+    }
+
+    fn skip(&mut self, len: usize) {
+        let len = core::cmp::min(len, self.len);
+        self.start = (self.start + len) % core::cmp::max(1, self.buffer.len());
+        self.len -= len;
+    }
+}
+
+/// Up channel 0 of a SEGGER RTT control block. Output is dropped while the probe doesn't keep up.
+pub struct Rtt {
+    channel: &'static mut rtt::Channel,
+}
+impl Rtt {
+    /// Sets the control block up with `buffer` as the channel's storage.
+    pub fn new(buffer: &'static mut [u8]) -> Self {
+        Self {
+            channel: rtt::init(buffer),
+        }
+    }
+}
+impl DebugSink for Rtt {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.channel.write(bytes);
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) {
+        if frame.len() <= self.channel.free() {
+            self.channel.write(frame);
+        }
+    }
+}
+
+mod rtt {
+    use core::ptr::{read_volatile, write_volatile};
+
+    #[repr(C)]
+    pub struct Channel {
+        name: *const u8,
+        buffer: *mut u8,
+        size: u32,
+        write: u32,
+        read: u32,
+        flags: u32,
+    }
+    impl Channel {
+        /// Number of bytes that can be written before the probe reads some.
+        pub fn free(&self) -> usize {
+            let size = self.size as usize;
+            if size == 0 {
+                return 0;
+            }
+            let write = unsafe { read_volatile(&self.write) } as usize;
+            let read = unsafe { read_volatile(&self.read) } as usize;
+            // one byte stays free to tell a full buffer from an empty one
+            (read + size - write - 1) % size
+        }
+
+        pub fn write(&mut self, bytes: &[u8]) {
+            let size = self.size as usize;
+            let free = self.free();
+            let mut write = unsafe { read_volatile(&self.write) } as usize;
+            for byte in bytes.iter().take(free) {
+                unsafe { write_volatile(self.buffer.add(write), *byte) };
+                write = (write + 1) % size;
+            }
+            unsafe { write_volatile(&mut self.write, write as u32) };
+        }
+    }
+
+    #[repr(C)]
+    struct ControlBlock {
+        id: [u8; 16],
+        max_up_buffers: u32,
+        max_down_buffers: u32,
+        up: Channel,
+    }
+
+    /// Found by the probe by scanning RAM for its id.
+    #[no_mangle]
+    static mut _SEGGER_RTT: ControlBlock = ControlBlock {
+        id: [0; 16],
+        max_up_buffers: 1,
+        max_down_buffers: 0,
+        up: Channel {
+            name: b"Terminal\0".as_ptr(),
+            buffer: core::ptr::null_mut(),
+            size: 0,
+            write: 0,
+            read: 0,
+            flags: 0,
+        },
+    };
+
+    pub fn init(buffer: &'static mut [u8]) -> &'static mut Channel {
+        unsafe {
+            let cb = &mut _SEGGER_RTT;
+            cb.up.buffer = buffer.as_mut_ptr();
+            cb.up.size = buffer.len() as u32;
+            // the id is written last, so the probe never finds a half initialized block
+            for (dst, src) in cb.id.iter_mut().zip(b"SEGGER RTT\0\0\0\0\0\0") {
+                write_volatile(dst, *src);
+            }
+            &mut cb.up
+        }
+    }
+}
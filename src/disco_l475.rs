@@ -90,14 +90,6 @@ pub fn init() -> (impl usb_device::class_prelude::UsbBus,(),(),()) {
 pub async fn trigger(_ctx: ()) {
 
 }
-pub fn consume_debug(_f: impl FnMut(&[u8])->usize) {
-
-}
-
-#[macro_export]
-macro_rules! dbgprint {
-    ($($arg:tt)*) => {{}};
-}
 
 macro_rules! impl_capabilities {
     ($name:ty) => {
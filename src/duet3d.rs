@@ -1,7 +1,6 @@
 use cortex_m::peripheral::syst::SystClkSource;
 use usbd_dfu::Error;
-use atsam4e_hal::pac::{efc, EFC};
-pub use atsam4e_hal::dbgprint;
+use atsam4e_hal::pac::{efc, interrupt, Interrupt, EFC};
 use atsam4e_hal::pmc::{MainClock, PmcExt};
 use atsam4e_hal::time::U32Ext;
 use atsam4e_hal::usb::*;
@@ -9,8 +8,6 @@ use atsam4e_hal::gpio::GpioExt;
 
 use embedded_hal::digital::v2::OutputPin;
 
-pub use atsam4e_hal::debug_on_buffer::consume as consume_debug;
-
 #[cfg(feature = "bootloader")]
 type DFUImpl = DFUModeImpl;
 #[cfg(feature = "application")]
@@ -26,7 +23,9 @@ pub fn init() -> (
     let p = atsam4e_hal::pac::Peripherals::take().unwrap_or_else(|| unreachable!());
     let mut cp = cortex_m::Peripherals::take().unwrap_or_else(|| unreachable!());
 
-    p.WDT.mr.write(|w| w.wddis().set_bit());
+    #[cfg_attr(not(feature = "bootloader"), allow(unused_variables))]
+    let watchdog_reset = watchdog::caused_reset();
+    watchdog::start(&p.WDT);
 
     // configure the clocks
     let pmc = p.PMC.constrain(); // constrain comes form a trait in the sam4e hal
@@ -45,18 +44,90 @@ pub fn init() -> (
     cp.SYST.set_reload(clocks.master_clock.0 / (8 * 1_000));
     cp.SYST.clear_current();
     cp.SYST.enable_counter();
+    cp.SYST.enable_interrupt();
 
-    static mut DEBUG_BUFFER: [u8; 1024] = [0; 1024];
-    unsafe { atsam4e_hal::debug_on_buffer::setup_with_buffer(&mut DEBUG_BUFFER) };
+    #[cfg(any(feature = "debug-buffer", feature = "debug-cdc", feature = "debug-rtt"))]
+    {
+        static mut DEBUG_BUFFER: [u8; 1024] = [0; 1024];
+        crate::debug::install_buffered(unsafe { &mut DEBUG_BUFFER });
+    }
+    #[cfg(feature = "debug-uart")]
+    compile_error!("debug-uart is not wired on the duet3d, use one of the RAM backed debug features");
 
     #[cfg(feature = "application")]
     let dfu = DFURuntimeImpl { efc: p.EFC };
     #[cfg(feature = "bootloader")]
-    let dfu = DFUModeImpl { efc: p.EFC };
+    let dfu = DFUModeImpl {
+        efc: p.EFC,
+        watchdog_reset,
+    };
 
     (usb_device::bus::UsbBusAllocator::new(UsbBus::new(p.UDP, (DDP, DDM), clocks)), led, cp, dfu)
 }
 
+#[cortex_m_rt::exception]
+fn SysTick() {
+    crate::executor::tick();
+}
+
+#[interrupt]
+fn UDP() {
+    // the cause is only cleared by `UsbDevice::poll`, keep the line masked until then
+    cortex_m::peripheral::NVIC::mask(Interrupt::UDP);
+    crate::executor::wake();
+}
+
+/// Sleeps until USB or the SysTick need attention.
+pub async fn wait_for_event() {
+    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::UDP) };
+    crate::executor::wait_for_event().await
+}
+
+pub mod watchdog {
+    use atsam4e_hal::pac::{RSTC, WDT};
+
+    /// Starts the watchdog with a 4s timeout. The mode register can only be written once after
+    /// reset, so the application started by the bootloader keeps this configuration.
+    pub fn start(wdt: &WDT) {
+        // the slow clock runs at 32.768kHz: 32.768kHz / 128 = 256Hz, 1024 ticks make 4s
+        wdt.mr.write(|w| unsafe { w.wdv().bits(1024).wdd().bits(1024).wdrsten().set_bit() });
+    }
+
+    pub fn feed() {
+        let wdt = unsafe { &*WDT::ptr() };
+        wdt.cr.write_with_zero(|w| w.wdrstt().set_bit().key().passwd());
+    }
+
+    /// True if the last reset was triggered by the watchdog.
+    pub fn caused_reset() -> bool {
+        let rstc = unsafe { &*RSTC::ptr() };
+        // RSTTYP = 2: watchdog reset
+        rstc.sr.read().rsttyp().bits() == 2
+    }
+}
+
+/// Number of times the bootloader started the application without the application reporting
+/// itself as healthy. It is kept in a general purpose backup register so it survives resets.
+pub mod boot_attempts {
+    /// Number of attempts after which the bootloader no longer starts the application.
+    pub const LIMIT: u32 = 3;
+
+    const SYS_GPBR0: *mut u32 = 0x400E_1890 as *mut u32;
+
+    pub fn get() -> u32 {
+        unsafe { core::ptr::read_volatile(SYS_GPBR0) }
+    }
+
+    pub fn increment() {
+        unsafe { core::ptr::write_volatile(SYS_GPBR0, get().saturating_add(1)) }
+    }
+
+    /// Called by the application once it is up and running.
+    pub fn clear() {
+        unsafe { core::ptr::write_volatile(SYS_GPBR0, 0) }
+    }
+}
+
 pub fn reset() -> ! {
     let rstc = unsafe { &*atsam4e_hal::pac::RSTC::ptr() };
     rstc.cr.write_with_zero(|w| {
@@ -72,6 +143,31 @@ pub fn reset() -> ! {
     }
 }
 
+/// Start of the application, right after the 32K of the bootloader.
+#[cfg(feature = "bootloader")]
+const APPLICATION_START: u32 = 0x0040_8000;
+
+#[cfg(feature = "bootloader")]
+pub fn jump_to_application() -> ! {
+    boot_attempts::increment();
+    unsafe {
+        let mut cp = cortex_m::Peripherals::steal();
+        cp.SYST.disable_interrupt();
+        cp.SYST.disable_counter();
+        cortex_m::peripheral::NVIC::mask(Interrupt::UDP);
+
+        cp.SCB.vtor.write(APPLICATION_START);
+        cortex_m::asm::bootload(APPLICATION_START as *const u32)
+    }
+}
+
+/// Flash is written through the IAP routine, which returns once done: there never is an erase to
+/// wait for.
+#[cfg(feature = "bootloader")]
+pub async fn erase_ended() {
+    futures::future::pending().await
+}
+
 #[derive(Debug)]
 pub struct FlashInfo {
     pub id: u32,
@@ -150,19 +246,42 @@ impl usbd_dfu::runtime::DeviceFirmwareUpgrade for DFURuntimeImpl {
     }
 
     fn on_detach_request(&mut self, _timeout_ms: u16) {}
+
+    fn firmware_info(&mut self) -> Option<usbd_dfu::runtime::FirmwareInfo<'_>> {
+        // the bootloader doesn't keep a manifest on this platform
+        Some(crate::version::firmware_info(&[]))
+    }
 }
 
 pub struct DFUModeImpl {
     /// Embedded Flash Controller
     efc: EFC,
+    /// The application got stuck and was reset by the watchdog, it is not started again.
+    watchdog_reset: bool,
+}
+impl DFUModeImpl {
+    /// There is no minimum security version to raise on this platform.
+    pub async fn commit_security_version(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 impl_capabilities!(DFUModeImpl);
 impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
     const POLL_TIMEOUT: u32 = 1000;
+    const VENDOR_ERROR: Option<&'static str> = Some("Watchdog reset");
+
+    fn startup_error(&mut self) -> Option<Error> {
+        // DFU has no status for watchdog resets, it is told apart by its description
+        if self.watchdog_reset {
+            Some(Error::Vendor)
+        } else {
+            None
+        }
+    }
 
     fn is_firmware_valid(&mut self) -> bool {
         //TODO: actually validate the flash !
-        true
+        !self.watchdog_reset && boot_attempts::get() < boot_attempts::LIMIT
     }
 
     fn is_transfer_complete(&mut self) -> bool {
@@ -1,36 +1,103 @@
+use core::cell::RefCell;
 use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
+use cortex_m::interrupt::{self, Mutex};
 use pin_utils::pin_mut;
 
+/// Maximum number of tasks `run` can drive, one bit of `READY` each.
+pub const MAX_TASKS: usize = 32;
+/// Maximum number of timers pending at once. A timer that finds no room polls its task again on
+/// the next pass.
+const MAX_TIMERS: usize = 8;
+
+/// One bit per task, set when the task has been woken.
+static READY: AtomicU32 = AtomicU32::new(0);
+/// Milliseconds elapsed since startup, counted by `tick`.
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+const NO_TIMER: Option<(u32, Waker)> = None;
+static TIMERS: Mutex<RefCell<[Option<(u32, Waker)>; MAX_TIMERS]>> =
+    Mutex::new(RefCell::new([NO_TIMER; MAX_TIMERS]));
+
+/// A task handed to `run`. Tasks are pinned by the caller, usually on the stack of a diverging
+/// `main`, so no allocation is involved.
+pub type Task<'a> = Pin<&'a mut dyn Future<Output = ()>>;
+
+/// Wakes every task up. Interrupt handlers with no waker at hand call this.
+pub fn wake() {
+    READY.store(u32::MAX, Ordering::Release);
+}
+
 const VTABLE: RawWakerVTable = {
     unsafe fn clone(s: *const ()) -> RawWaker {
         RawWaker::new(s, &VTABLE)
     }
-    unsafe fn wake(_: *const ()) {}
-    unsafe fn wake_by_ref(_: *const ()) {}
+    unsafe fn wake(s: *const ()) {
+        READY.fetch_or(1 << (s as usize), Ordering::AcqRel);
+    }
+    unsafe fn wake_by_ref(s: *const ()) {
+        READY.fetch_or(1 << (s as usize), Ordering::AcqRel);
+    }
     unsafe fn drop(_: *const ()) {}
 
     RawWakerVTable::new(clone, wake, wake_by_ref, drop)
 };
 
+fn waker(id: usize) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &VTABLE)) }
+}
+
+/// Sleeps until an interrupt, unless a task has been woken in the meantime. With interrupts
+/// masked, a wake up landing between the check and the WFI still ends the sleep.
+fn sleep() {
+    interrupt::free(|_| {
+        if READY.load(Ordering::Acquire) == 0 {
+            cortex_m::asm::wfi();
+        }
+    });
+}
+
+/// Runs `tasks` forever, polling each one only when it has been woken. The core sleeps when no
+/// task is ready.
+pub fn run(tasks: &mut [Task]) -> ! {
+    assert!(tasks.len() <= MAX_TASKS);
+
+    let mut done = 0u32;
+    READY.store(u32::MAX, Ordering::Release);
+    loop {
+        let ready = READY.swap(0, Ordering::AcqRel) & !done;
+        for (id, task) in tasks.iter_mut().enumerate() {
+            if ready & (1 << id) == 0 {
+                continue;
+            }
+            let waker = waker(id);
+            if let Poll::Ready(()) = task.as_mut().poll(&mut Context::from_waker(&waker)) {
+                done |= 1 << id;
+            }
+        }
+        sleep();
+    }
+}
+
+/// Runs `t` to completion. The core sleeps while `t` is pending and nothing woke it.
 pub fn block_on<T>(t: T) -> T::Output
 where
     T: Future,
 {
-    let raw_waker = RawWaker::new(core::ptr::null(), &VTABLE);
     pin_mut!(t);
 
-    unsafe {
-        let waker = Waker::from_raw(raw_waker);
-        let mut ctx = Context::from_waker(&waker);
-
-        loop {
-            match t.as_mut().poll(&mut ctx) {
-                Poll::Ready(out) => return out,
-                Poll::Pending => {}
-            }
+    let waker = waker(0);
+    let mut ctx = Context::from_waker(&waker);
+    loop {
+        READY.store(0, Ordering::Release);
+        match t.as_mut().poll(&mut ctx) {
+            Poll::Ready(out) => return out,
+            Poll::Pending => {}
         }
+        sleep();
     }
 }
 
@@ -38,16 +105,114 @@ pub fn poll_once<T>(t: T) -> Option<T::Output>
 where
     T: Future,
 {
-    let raw_waker = RawWaker::new(core::ptr::null(), &VTABLE);
     pin_mut!(t);
 
-    unsafe {
-        let waker = Waker::from_raw(raw_waker);
-        let mut ctx = Context::from_waker(&waker);
+    let waker = waker(0);
+    let mut ctx = Context::from_waker(&waker);
+    match t.as_mut().poll(&mut ctx) {
+        Poll::Ready(out) => Some(out),
+        Poll::Pending => None,
+    }
+}
+
+/// Yields to the executor until something calls `wake`.
+pub async fn wait_for_event() {
+    let mut waited = false;
+    futures::future::poll_fn(|_| {
+        if waited {
+            Poll::Ready(())
+        } else {
+            waited = true;
+            Poll::Pending
+        }
+    })
+    .await
+}
 
-        match t.as_mut().poll(&mut ctx) {
-            Poll::Ready(out) => Some(out),
-            Poll::Pending => None,
+/// Lets the other tasks run before resuming.
+pub async fn yield_now() {
+    let mut yielded = false;
+    futures::future::poll_fn(|ctx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            ctx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Milliseconds elapsed since startup. Wraps around after about 49 days.
+pub fn now() -> u32 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// True if `a` comes before `b`, accounting for wrap around.
+fn is_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Advances time by one millisecond and wakes the timers that are due. Called from the platform's
+/// SysTick handler.
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::AcqRel).wrapping_add(1);
+    interrupt::free(|cs| {
+        for slot in TIMERS.borrow(cs).borrow_mut().iter_mut() {
+            match slot {
+                Some((deadline, _)) if !is_before(now, *deadline) => {}
+                _ => continue,
+            }
+            if let Some((_, waker)) = slot.take() {
+                waker.wake();
+            }
         }
+    });
+}
+
+/// A future completing at a given time.
+pub struct Timer {
+    deadline: u32,
+}
+impl Timer {
+    /// Completes `ms` milliseconds from now.
+    pub fn after(ms: u32) -> Self {
+        Self::at(now().wrapping_add(ms))
+    }
+    /// Completes once `now()` reaches `deadline`.
+    pub fn at(deadline: u32) -> Self {
+        Self { deadline }
+    }
+}
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<()> {
+        let deadline = self.deadline;
+        interrupt::free(|cs| {
+            if !is_before(now(), deadline) {
+                return Poll::Ready(());
+            }
+
+            let mut timers = TIMERS.borrow(cs).borrow_mut();
+            let registered = timers.iter_mut().find(|slot| match slot {
+                Some((_, waker)) => waker.will_wake(ctx.waker()),
+                None => false,
+            });
+            match registered {
+                // a task only needs to be woken for its earliest timer
+                Some(Some((current, _))) => {
+                    if is_before(deadline, *current) {
+                        *current = deadline;
+                    }
+                }
+                _ => match timers.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => *slot = Some((deadline, ctx.waker().clone())),
+                    None => ctx.waker().wake_by_ref(),
+                },
+            }
+            Poll::Pending
+        })
     }
 }
@@ -6,15 +6,19 @@
 
 extern crate alloc;
 
+#[macro_use]
+pub mod debug;
 pub mod executor;
 pub mod trace;
+pub mod version;
+
+#[cfg(feature = "use-defmt")]
+mod logger;
 
 #[cfg(feature = "duet3d")]
 pub mod duet3d;
 #[cfg(feature = "duet3d")]
 pub use duet3d as platform;
-#[cfg(feature = "duet3d")]
-pub use duet3d::dbgprint;
 
 //usb's not yet supported on stm32l4x5
 //#[cfg(feature = "disco-l475")]
@@ -0,0 +1,68 @@
+//! `defmt` transport: each frame is encoded in RAM then handed whole to the installed
+//! `DebugSink`, whichever `debug-*` feature selected it. `dbgprint!` logs through defmt too, so
+//! nothing else writes to the sink.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[defmt::global_logger]
+struct Logger;
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut INTERRUPTS_ACTIVE: bool = false;
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+/// Longest encoded frame, longer ones are dropped.
+const FRAME_LENGTH: usize = 256;
+
+/// Frame being encoded. `FRAME_LEN` is `None` once it outgrew the buffer.
+static mut FRAME: [u8; FRAME_LENGTH] = [0; FRAME_LENGTH];
+static mut FRAME_LEN: Option<usize> = Some(0);
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = cortex_m::register::primask::read();
+        cortex_m::interrupt::disable();
+
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly")
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+
+        unsafe {
+            INTERRUPTS_ACTIVE = primask.is_active();
+            FRAME_LEN = Some(0);
+            ENCODER.start_frame(write);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        ENCODER.end_frame(write);
+        if let Some(len) = FRAME_LEN {
+            crate::debug::write_frame(&FRAME[..len]);
+        }
+        TAKEN.store(false, Ordering::Relaxed);
+        if INTERRUPTS_ACTIVE {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, write);
+    }
+}
+
+fn write(bytes: &[u8]) {
+    unsafe {
+        FRAME_LEN = FRAME_LEN
+            .map(|len| len + bytes.len())
+            .filter(|end| *end <= FRAME_LENGTH)
+            .map(|end| {
+                FRAME[end - bytes.len()..end].copy_from_slice(bytes);
+                end
+            });
+    }
+}
+
+defmt::timestamp!("{=u32:ms}", crate::executor::now());
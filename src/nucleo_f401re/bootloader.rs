@@ -1,5 +1,10 @@
 use core::convert::TryFrom;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Poll;
+use futures::task::AtomicWaker;
+use stm32f4xx_hal::pac::flash::cr::PSIZE_A;
+use stm32f4xx_hal::pac::{interrupt, Interrupt};
 use stm32f4xx_hal::rcc::RccExt;
 use usbd_dfu::Result;
 
@@ -21,7 +26,27 @@ const SECTORS: [(usize, usize); 8] = [
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Sector(usize);
 impl Sector {
-    fn is_erased(&self) -> bool {
+    pub fn start(&self) -> usize {
+        SECTORS[self.0].0
+    }
+    pub fn size(&self) -> usize {
+        SECTORS[self.0].1
+    }
+    /// Worst case time, in milliseconds, to erase the sector with the parallelism `range` allows.
+    pub fn erase_time(&self, range: VoltageRange) -> u32 {
+        let (small, medium, large) = match range {
+            VoltageRange::Range1 => (800, 2400, 4000),
+            VoltageRange::Range2 => (600, 1400, 2600),
+            // erasing with a x64 parallelism is faster, the x32 figures are an upper bound
+            VoltageRange::Range3 | VoltageRange::ExternalVpp => (500, 1100, 2000),
+        };
+        match self.size() {
+            0..=0x4000 => small,
+            0x4001..=0x1_0000 => medium,
+            _ => large,
+        }
+    }
+    pub fn is_erased(&self) -> bool {
         let arr = self.region();
         arr.iter().cloned().all(|b| b == 0xFFFF_FFFF)
     }
@@ -65,25 +90,136 @@ impl Iterator for Sector {
     }
 }
 
+/// Supply voltage range of the device. It bounds how many bytes flash can program at once.
+#[derive(Debug, Clone, Copy)]
+pub enum VoltageRange {
+    /// 1.8V to 2.1V, programs by bytes.
+    Range1,
+    /// 2.1V to 2.7V, programs by half-words.
+    Range2,
+    /// 2.7V to 3.6V, programs by words.
+    Range3,
+    /// 2.7V to 3.6V with 8V to 9V applied on VPP, programs by double words.
+    ExternalVpp,
+}
+impl VoltageRange {
+    /// Widest access, in bytes, also the parallelism of erases.
+    fn max_width(self) -> usize {
+        match self {
+            VoltageRange::Range1 => 1,
+            VoltageRange::Range2 => 2,
+            VoltageRange::Range3 => 4,
+            VoltageRange::ExternalVpp => 8,
+        }
+    }
+}
+
+/// Maximum number of bytes written by a single call to `Memory::program`. This bounds the time
+/// spent without servicing USB.
+const MAX_RUN_LENGTH: usize = 1024;
+
+const FLASH_SR: *const u32 = 0x4002_3C0C as *const u32;
+const FLASH_SR_BSY: u32 = 1 << 16;
+const IWDG_KR: *mut u32 = 0x4000_3000 as *mut u32;
+const IWDG_KR_RELOAD: u32 = 0xAAAA;
+
+/// Waits for the flash to be idle and returns the status register. The watchdog is fed meanwhile.
+unsafe fn wait_ready() -> u32 {
+    loop {
+        core::ptr::write_volatile(IWDG_KR, IWDG_KR_RELOAD);
+        let sr = core::ptr::read_volatile(FLASH_SR);
+        if sr & FLASH_SR_BSY == 0 {
+            return sr;
+        }
+    }
+}
+
+const SCB_VTOR: *mut u32 = 0xE000_ED08 as *mut u32;
+
+/// Copies the code and read-only data linked to RAM by `nucleo-f401re-bootloader.x`, and the
+/// vector table, which then replaces the one in flash. Runs from flash before the runtime
+/// initialises RAM, so it can't call anything in `.text` until it is done.
+#[cortex_m_rt::pre_init]
+#[link_section = ".Reset"]
+unsafe fn load_ram_text() {
+    extern "C" {
+        static mut __sramvectors: u32;
+        static mut __eramvectors: u32;
+        static __svectors: u32;
+        static mut __sramtext: u32;
+        static mut __eramtext: u32;
+        static __siramtext: u32;
+    }
+    copy_words(&mut __sramvectors, &mut __eramvectors, &__svectors);
+    copy_words(&mut __sramtext, &mut __eramtext, &__siramtext);
+    core::ptr::write_volatile(SCB_VTOR, &__sramvectors as *const u32 as u32);
+}
+
+/// Fills `dst` up to `dst_end` with the words from `src`. Kept in flash along with `load_ram_text`.
+#[link_section = ".Reset"]
+unsafe fn copy_words(dst: *mut u32, dst_end: *mut u32, src: *const u32) {
+    let length = (dst_end as usize - dst as usize) / 4;
+    for i in 0..length {
+        core::ptr::write_volatile(dst.add(i), core::ptr::read_volatile(src.add(i)));
+    }
+}
+
+/// Woken by the flash interrupt when an erase completes.
+static FLASH_WAKER: AtomicWaker = AtomicWaker::new();
+/// Set by the flash interrupt, taken by `erase_ended`.
+static ERASE_ENDED: AtomicBool = AtomicBool::new(false);
+
+#[interrupt]
+fn FLASH() {
+    let flash = unsafe { &*stm32f4xx_hal::pac::FLASH::ptr() };
+    flash
+        .cr
+        .modify(|_, w| w.eopie().clear_bit().errie().clear_bit());
+    ERASE_ENDED.store(true, Ordering::Release);
+    FLASH_WAKER.wake();
+}
+
+/// Completes once an erase started by `Memory::poll` ended, its outcome being left to the next
+/// call to `Memory::poll`. Lets a task that can't hold on to the `Memory` carry on as soon as
+/// flash is available again.
+pub async fn erase_ended() {
+    futures::future::poll_fn(|ctx| {
+        FLASH_WAKER.register(ctx.waker());
+        if ERASE_ENDED.swap(false, Ordering::AcqRel) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
 #[derive(Debug)]
 enum MemoryState {
+    /// The erase starts on the next call to `poll`, giving the caller a chance to complete pending
+    /// USB transactions first.
+    ErasePending(Sector),
     Erasing(Sector),
-    Programming {
-        addr: usize,
-        to_write: usize,
-        src: [u8; 4],
-    },
+    Programmed(Result<usize>),
     Idle,
 }
 
+/// Flash operations are started by `start_erase` and `start_program`, and their outcome is
+/// collected by `poll`, or awaited with `erase` and `program`. Programming is short and completes
+/// synchronously. An erase goes on in the background and raises the flash interrupt when it ends,
+/// so the executor sleeps meanwhile. Flash can't be read until then on the single bank F401, the
+/// bootloader runs from RAM to keep servicing USB, see `load_ram_text`.
 pub struct Memory {
     flash: stm32f4xx_hal::pac::FLASH,
+    range: VoltageRange,
     state: MemoryState,
 }
 impl Memory {
-    pub fn new(flash: stm32f4xx_hal::pac::FLASH) -> Self {
+    pub fn new(flash: stm32f4xx_hal::pac::FLASH, range: VoltageRange) -> Self {
+        unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::FLASH) };
         Self {
             flash,
+            range,
             state: MemoryState::Idle,
         }
     }
@@ -103,12 +239,36 @@ impl Memory {
         //crate::dbgprint!(".");
         match self.state {
             MemoryState::Idle => Poll::Ready(Ok(0)), /* unused */
+            MemoryState::ErasePending(sector) => {
+                let psize = psize(self.range.max_width());
+                self.flash.cr.modify(|_, w| unsafe {
+                    w.ser()
+                        .set_bit()
+                        .snb()
+                        .bits(sector.0 as u8)
+                        .psize()
+                        .variant(psize)
+                        .eopie()
+                        .set_bit()
+                        .errie()
+                        .set_bit()
+                });
+                self.flash.cr.modify(|_, w| w.strt().set_bit());
+
+                self.state = MemoryState::Erasing(sector);
+                Poll::Pending
+            }
             MemoryState::Erasing(sector) => {
-                //crate::dbgprint!("{:x?}\r\n", self.state);
+                //crate::dbgprint!("{:?}", self.state);
                 let sr = self.flash.sr.read();
                 if sr.bsy().bit_is_set() {
                     Poll::Pending
                 } else {
+                    self.flash
+                        .cr
+                        .modify(|_, w| w.ser().clear_bit().eopie().clear_bit().errie().clear_bit());
+                    self.flash.sr.write(|w| w.eop().set_bit());
+
                     let res = if sr.wrperr().bit_is_set() {
                         Err(usbd_dfu::Error::Write)
                     } else if sr.operr().bit_is_set() {
@@ -122,112 +282,194 @@ impl Memory {
                     Poll::Ready(res)
                 }
             }
-            MemoryState::Programming {
-                addr,
-                to_write,
-                src,
-            } => {
-                let sr = self.flash.sr.read();
-                if sr.bsy().bit_is_set() {
-                    Poll::Pending
-                } else {
-                    let dst = unsafe { core::slice::from_raw_parts(addr as *const u8, to_write) };
-
-                    let res = if sr.wrperr().bit_is_set() {
-                        Err(usbd_dfu::Error::Write)
-                    } else if sr.operr().bit_is_set() {
-                        Err(usbd_dfu::Error::Programming)
-                    } else if dst != &src[..to_write] {
-                        Err(usbd_dfu::Error::Verify)
-                    } else {
-                        Ok(to_write)
-                    };
-                    self.state = MemoryState::Idle;
-                    Poll::Ready(res)
-                }
+            MemoryState::Programmed(res) => {
+                self.state = MemoryState::Idle;
+                Poll::Ready(res)
             }
         }
     }
 
-    pub fn erase(&mut self, sector: Sector) -> Poll<usbd_dfu::Error> {
+    pub fn start_erase(&mut self, sector: Sector) -> Poll<usbd_dfu::Error> {
         match self.unlock() {
             Ok(()) => {}
             Err(e) => return Poll::Ready(e),
         }
 
-        self.flash
-            .cr
-            .modify(|_, w| unsafe { w.ser().set_bit().snb().bits(sector.0 as u8) });
-        self.flash.cr.modify(|_, w| w.strt().set_bit());
-
-        self.state = MemoryState::Erasing(sector);
+        self.state = MemoryState::ErasePending(sector);
         Poll::Pending
     }
 
-    pub fn program(&mut self, addr: usize, src: &[u8]) -> Poll<usbd_dfu::Error> {
+    /// Erases `sector`, waiting for the flash interrupt.
+    pub async fn erase(&mut self, sector: Sector) -> Result<()> {
+        if let Poll::Ready(e) = self.start_erase(sector) {
+            return Err(e);
+        }
+        self.completion().await.map(drop)
+    }
+
+    /// Programs `src` at `addr`, see `start_program`. Returns the number of bytes written.
+    pub async fn program(&mut self, addr: usize, src: &[u8]) -> Result<usize> {
+        if let Poll::Ready(e) = self.start_program(addr, src) {
+            return Err(e);
+        }
+        self.completion().await
+    }
+
+    fn completion(&mut self) -> impl Future<Output = Result<usize>> + '_ {
+        futures::future::poll_fn(move |ctx| {
+            FLASH_WAKER.register(ctx.waker());
+            self.poll()
+        })
+    }
+
+    /// Time, in milliseconds, the pending operation is expected to take if it is a long one.
+    pub fn busy_time(&self) -> Option<u32> {
+        match self.state {
+            MemoryState::ErasePending(sector) | MemoryState::Erasing(sector) => {
+                Some(sector.erase_time(self.range))
+            }
+            _ => None,
+        }
+    }
+
+    /// Programs as much of `src` as possible, up to `MAX_RUN_LENGTH` bytes, using the widest
+    /// accesses allowed by the voltage range and the alignment. The number of bytes written is
+    /// returned by the next call to `poll`.
+    pub fn start_program(&mut self, addr: usize, src: &[u8]) -> Poll<usbd_dfu::Error> {
         match self.unlock() {
             Ok(()) => {}
             Err(e) => return Poll::Ready(e),
         }
-        let mut to_write = if addr & 1 == 1 {
-            1
-        } else if addr & 2 == 2 {
-            2
-        } else {
-            usize::min(src.len(), 4)
-        };
-        if to_write == 3 {
-            to_write = 2;
+
+        let src = &src[..usize::min(src.len(), MAX_RUN_LENGTH)];
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < src.len() {
+            let dst = addr + written;
+            let width = self.width(dst, src.len() - written);
+            if let Err(e) = self.program_unit(dst, &src[written..written + width]) {
+                res = Err(e);
+                break;
+            }
+            written += width;
         }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
 
-        use stm32f4xx_hal::pac::flash::cr::PSIZE_A;
-        let psize = match to_write {
-            1 => PSIZE_A::PSIZE8,
-            2 => PSIZE_A::PSIZE16,
-            4 => PSIZE_A::PSIZE32,
-            _ => unreachable!(),
-        };
+        self.state = MemoryState::Programmed(res.map(|_| written));
+        Poll::Pending
+    }
+
+    /// Widest access allowed at `addr` for at most `len` bytes.
+    fn width(&self, addr: usize, len: usize) -> usize {
+        let mut width = self.range.max_width();
+        while width > 1 && (addr % width != 0 || len < width) {
+            width /= 2;
+        }
+        width
+    }
+
+    fn program_unit(&mut self, addr: usize, src: &[u8]) -> Result<()> {
+        let psize = psize(src.len());
 
         self.flash
             .cr
             .modify(|_, w| w.pg().set_bit().psize().variant(psize));
 
+        let mut bytes = [0; 8];
+        bytes[..src.len()].copy_from_slice(src);
         unsafe {
             match psize {
-                PSIZE_A::PSIZE8 => {
-                    let ptr = addr as *mut u8;
-                    core::ptr::write_volatile(ptr, src[0]);
-                }
+                PSIZE_A::PSIZE8 => core::ptr::write_volatile(addr as *mut u8, bytes[0]),
                 PSIZE_A::PSIZE16 => {
-                    let ptr = addr as *mut u16;
-                    let src = core::ptr::read(src.as_ptr() as *const u16);
-                    core::ptr::write_volatile(ptr, src);
+                    let value = u16::from_ne_bytes([bytes[0], bytes[1]]);
+                    core::ptr::write_volatile(addr as *mut u16, value)
                 }
                 PSIZE_A::PSIZE32 => {
-                    let ptr = addr as *mut u32;
-                    let src = core::ptr::read(src.as_ptr() as *const u32);
-                    core::ptr::write_volatile(ptr, src);
+                    let value = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    core::ptr::write_volatile(addr as *mut u32, value)
+                }
+                PSIZE_A::PSIZE64 => {
+                    // the controller assembles a double word from two word writes, low word first
+                    let low = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    let high = u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+                    core::ptr::write_volatile(addr as *mut u32, low);
+                    cortex_m::asm::isb();
+                    core::ptr::write_volatile((addr + 4) as *mut u32, high)
                 }
-                PSIZE_A::PSIZE64 => unreachable!(),
             }
         }
 
-        let mut src_owned = [0; 4];
-        src_owned[..to_write].copy_from_slice(&src[..to_write]);
-        self.state = MemoryState::Programming {
-            addr,
-            to_write,
-            src: src_owned,
-        };
-        Poll::Pending
+        unsafe { wait_ready() };
+        let sr = self.flash.sr.read();
+        let dst = unsafe { core::slice::from_raw_parts(addr as *const u8, src.len()) };
+
+        if sr.wrperr().bit_is_set() {
+            Err(usbd_dfu::Error::Write)
+        } else if sr.operr().bit_is_set()
+            || sr.pgaerr().bit_is_set()
+            || sr.pgperr().bit_is_set()
+            || sr.pgserr().bit_is_set()
+        {
+            Err(usbd_dfu::Error::Programming)
+        } else if dst != src {
+            Err(usbd_dfu::Error::Verify)
+        } else {
+            Ok(())
+        }
     }
 }
 
+/// Program size, or erase parallelism, for accesses of `width` bytes.
+fn psize(width: usize) -> PSIZE_A {
+    match width {
+        1 => PSIZE_A::PSIZE8,
+        2 => PSIZE_A::PSIZE16,
+        4 => PSIZE_A::PSIZE32,
+        _ => PSIZE_A::PSIZE64,
+    }
+}
+
+/// OTP blocks 0 to 15, their lock bytes follow. Each programmed byte counts for one security
+/// version: OTP can't be erased, so the count only ever goes up.
+const OTP_START: usize = 0x1FFF_7800;
+const OTP_LENGTH: usize = 16 * 32;
+
+/// Highest security version the OTP area can record.
+pub const MAX_SECURITY_VERSION: u32 = OTP_LENGTH as u32;
+
+/// Lowest security version an image must have to be installed or started.
+pub fn minimum_security_version() -> u32 {
+    let otp = unsafe { core::slice::from_raw_parts(OTP_START as *const u8, OTP_LENGTH) };
+    otp.iter().take_while(|byte| **byte != 0xFF).count() as u32
+}
+
+/// Raises the minimum security version to `version`. A version past `MAX_SECURITY_VERSION` can't
+/// be recorded and is an error.
+pub async fn raise_minimum_security_version(memory: &mut Memory, version: u32) -> Result<()> {
+    if version > MAX_SECURITY_VERSION {
+        return Err(usbd_dfu::Error::File);
+    }
+    let current = minimum_security_version() as usize;
+    let target = version as usize;
+    if target <= current {
+        return Ok(());
+    }
+
+    const PROGRAMMED: [u8; OTP_LENGTH] = [0; OTP_LENGTH];
+    memory
+        .program(OTP_START + current, &PROGRAMMED[current..target])
+        .await
+        .map(drop)
+}
+
 pub fn jump_to_application() -> ! {
+    super::boot_attempts::increment();
     unsafe {
         let mut cp = cortex_m::Peripherals::steal();
-        cp.SYST.disable_interrupt(); // it wasn't enabled but better safe than sorry
+        cp.SYST.disable_interrupt();
         cp.SYST.disable_counter();
+        cortex_m::peripheral::NVIC::mask(Interrupt::OTG_FS);
+        cortex_m::peripheral::NVIC::mask(Interrupt::FLASH);
 
         let dp = stm32f4xx_hal::stm32::Peripherals::steal();
         dp.RCC.ahb1rstr.write_with_zero(|w| w.gpioarst().set_bit());
@@ -238,9 +480,9 @@ pub fn jump_to_application() -> ! {
         }
         let clocks = dp.RCC.constrain().cfgr.freeze();
         #[cfg(feature = "debug-buffer")]
-        dbgprint!("clocks {:?}\r\n", clocks.hclk()); //
-        dbgprint!("clocks {:?}\r\n", clocks.sysclk()); //
-        dbgprint!("clocks {:?}\r\n", clocks.pll48clk()); //
+        dbgprint!("clocks {}", clocks.hclk().0); //
+        dbgprint!("clocks {}", clocks.sysclk().0); //
+        dbgprint!("clocks {:?}", clocks.pll48clk().map(|clk| clk.0)); //
 
         // This for some reason breaks the system.
         //dp.FLASH.acr.modify(|_, w| {
@@ -13,6 +13,13 @@ macro_rules! impl_capabilities {
     };
 }
 
+use dfu_image::{HashAlgorithm, Manifest, ManifestError, MANIFEST_LENGTH};
+
+#[cfg(not(feature = "use-sha256"))]
+const HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Sha1;
+#[cfg(feature = "use-sha256")]
+const HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+
 #[cfg(not(feature = "use-sha256"))]
 const HASH_LENGTH: usize = 20;
 #[cfg(feature = "use-sha256")]
@@ -20,24 +27,30 @@ const HASH_LENGTH: usize = 32;
 
 type Hash = [u8; HASH_LENGTH];
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct Manifest {
-    pub length: usize,
-    pub hash: Hash,
-}
-impl Manifest {
-    fn get() -> &'static Manifest {
-        unsafe { &*(MANIFEST_REGION_START as *const Manifest) }
-    }
+/// The manifest of the installed image.
+fn installed_manifest() -> Result<Manifest, ManifestError> {
+    let bytes = unsafe { &*(MANIFEST_REGION_START as *const [u8; MANIFEST_LENGTH]) };
+    Manifest::from_bytes(bytes)
 }
 
 #[cfg(feature = "application")]
 pub mod runtime {
-    pub struct DFURuntimeImpl;
+    use usbd_dfu::runtime::FirmwareInfo;
+
+    #[link_section = ".image_header"]
+    #[used]
+    static IMAGE_HEADER: dfu_image::ImageHeader =
+        dfu_image::ImageHeader::new(crate::version::SECURITY_VERSION);
+
+    pub struct DFURuntimeImpl {
+        /// It doesn't change while the application runs.
+        manifest: Option<dfu_image::Manifest>,
+    }
     impl DFURuntimeImpl {
-        pub async fn read_manifest(&self) -> &'static super::Manifest {
-            super::Manifest::get()
+        pub fn new() -> Self {
+            Self {
+                manifest: super::installed_manifest().ok(),
+            }
         }
     }
     impl_capabilities!(DFURuntimeImpl);
@@ -47,6 +60,11 @@ pub mod runtime {
         }
 
         fn on_detach_request(&mut self, _timeout_ms: u16) {}
+
+        fn firmware_info(&mut self) -> Option<FirmwareInfo<'_>> {
+            let manifest = self.manifest.as_ref()?;
+            Some(crate::version::firmware_info(manifest.hash()))
+        }
     }
 }
 
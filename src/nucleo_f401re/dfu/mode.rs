@@ -1,11 +1,17 @@
-use core::{convert::TryFrom, task::Poll};
+use core::{convert::TryFrom, mem::MaybeUninit, task::Poll};
 
 use stm32f4xx_hal::gpio::{gpioc::PC13, Floating, Input};
 use usbd_dfu::{Capabilities, Result};
 
-use super::{Hash, Manifest};
+use dfu_image::{ImageHeader, ImageType, Manifest, FLAG_SIGNED, MANIFEST_LENGTH, MANIFEST_MAGIC};
+
+use super::{installed_manifest, Hash, HASH_ALGORITHM};
 use crate::platform::{
-    bootloader::{Memory, Sector, APPLICATION_LENGTH, APPLICATION_REGION_START},
+    boot_attempts,
+    bootloader::{
+        minimum_security_version, raise_minimum_security_version, Memory, Sector,
+        APPLICATION_LENGTH, APPLICATION_REGION_START, MAX_SECURITY_VERSION,
+    },
     MANIFEST_REGION_START,
 };
 
@@ -21,10 +27,6 @@ impl ApplicationRef {
             ))
         }
     }
-    fn get() -> Self {
-        let manifest = Manifest::get();
-        Self::get_with_length(manifest.length)
-    }
     pub fn compute_hash(&self) -> Hash {
         #[cfg(not(feature = "use-sha256"))]
         {
@@ -39,22 +41,102 @@ impl ApplicationRef {
     }
 }
 
+/// Largest amount of data kept for a sector that is updated in place, that is the 16 KiB sectors.
+/// Sectors 4 to 7 are larger than the RAM: only the start of them is kept. A mismatch found past
+/// it could not be recovered from, so they are only updated in place when the image is the
+/// installed one, and erased otherwise unless they already are blank.
+const REPLAY_LENGTH: usize = 16 * 1024;
+
+/// Copy of the data received for the sector being updated in place, up to `REPLAY_LENGTH` bytes.
+/// It is programmed back if the sector needs to be erased halfway through.
+static mut REPLAY: MaybeUninit<[u8; REPLAY_LENGTH]> = MaybeUninit::uninit();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SectorState {
+    /// The sector is blank, data is programmed as it comes.
+    Erased,
+    /// The sector still holds its previous content. Bytes that already match are skipped, bytes
+    /// that only need bits cleared are programmed over, and the sector is erased as soon as a bit
+    /// needs to go from 0 to 1.
+    InPlace,
+}
+
+/// Number of blocks the host can send ahead of what is being programmed.
+const BLOCK_COUNT: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    data: [u8; DFUModeImpl::TRANSFER_SIZE as usize],
+    len: usize,
+}
+
+/// Blocks received from the host and waiting to be programmed.
+#[derive(Clone, Debug)]
+struct BlockRing {
+    blocks: [Block; BLOCK_COUNT],
+    head: usize,
+    count: usize,
+}
+impl BlockRing {
+    fn new() -> Self {
+        Self {
+            blocks: [Block {
+                data: [0; DFUModeImpl::TRANSFER_SIZE as usize],
+                len: 0,
+            }; BLOCK_COUNT],
+            head: 0,
+            count: 0,
+        }
+    }
+    fn is_full(&self) -> bool {
+        self.count == BLOCK_COUNT
+    }
+    fn front(&self) -> Option<&Block> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(&self.blocks[self.head])
+        }
+    }
+    fn push(&mut self, buf: &[u8]) -> Result<()> {
+        if self.is_full() {
+            return Err(usbd_dfu::Error::Unknown);
+        }
+        let block = &mut self.blocks[(self.head + self.count) % BLOCK_COUNT];
+        block
+            .data
+            .get_mut(..buf.len())
+            .ok_or(usbd_dfu::Error::Unknown)?
+            .copy_from_slice(buf);
+        block.len = buf.len();
+        self.count += 1;
+        Ok(())
+    }
+    fn pop(&mut self) {
+        if self.count != 0 {
+            self.head = (self.head + 1) % BLOCK_COUNT;
+            self.count -= 1;
+        }
+    }
+}
+
+/// Progress on the block at the front of the ring. `wr_ptr` is the offset in that block.
 #[derive(Clone, Debug)]
 enum ProgramState {
     AwaitData,
     AwaitEraseBeforeProgram {
-        data: [u8; DFUModeImpl::TRANSFER_SIZE as usize],
-        data_len: usize,
         wr_ptr: usize,
     },
+    AwaitReplay {
+        wr_ptr: usize,
+        replay_ptr: usize,
+    },
     AwaitProgram {
-        data: [u8; DFUModeImpl::TRANSFER_SIZE as usize],
-        data_len: usize,
         wr_ptr: usize,
     },
     AwaitErase,
     AwaitProgramManifest {
-        data: [u8; core::mem::size_of::<Manifest>()],
+        data: [u8; MANIFEST_LENGTH],
         wr_ptr: usize,
     },
     Done,
@@ -63,204 +145,319 @@ enum ProgramState {
 #[derive(Clone, Debug)]
 struct Program {
     current_sector: Sector,
+    sector_state: SectorState,
     addr: usize,
+    blocks: BlockRing,
+    /// Set once the host has sent the whole image. The manifest is written as soon as the last
+    /// block is programmed.
+    finalizing: bool,
+    /// Manifest the host sent ahead of the image, the programmed image must match it.
+    expected: Manifest,
+    /// Address past the end of the image, from `expected`.
+    end: usize,
+    /// `expected` describes the installed image, which is intact: every sector is compared
+    /// against flash block by block, the large ones included, and only erased on a mismatch.
+    identical: bool,
     state: ProgramState,
 }
 impl Program {
     fn new(memory: &mut Memory, buf: &[u8]) -> Result<Self> {
-        if buf.len() >= APPLICATION_LENGTH {
+        let (expected, buf) = split_manifest(buf)?;
+        // an image that can't fit is turned down while the installed one is still intact
+        if expected.load_address != APPLICATION_REGION_START as u32
+            || expected.image_length as usize > APPLICATION_LENGTH
+        {
             return Err(usbd_dfu::Error::Address);
         }
-        let data_len = buf.len();
-        let mut data = [0; DFUModeImpl::TRANSFER_SIZE as usize];
-        data[..data_len].copy_from_slice(buf);
-
-        // erase first sector
-        let current_sector = Sector::try_from(APPLICATION_REGION_START)?;
-        match memory.erase(current_sector) {
-            Poll::Ready(e) => return Err(e),
-            Poll::Pending => {}
+        // so is a rollback, or a version the minimum could never be raised to
+        if expected.security_version < minimum_security_version()
+            || expected.security_version > MAX_SECURITY_VERSION
+        {
+            return Err(usbd_dfu::Error::File);
         }
+        let end = APPLICATION_REGION_START + expected.image_length as usize;
+        let identical = is_installed(&expected);
 
-        Ok(Self {
+        let current_sector = Sector::try_from(APPLICATION_REGION_START)?;
+        let mut program = Self {
             current_sector,
+            sector_state: SectorState::Erased,
             addr: APPLICATION_REGION_START,
-            state: ProgramState::AwaitEraseBeforeProgram {
-                data,
-                data_len,
-                wr_ptr: 0,
-            },
-        })
+            blocks: BlockRing::new(),
+            finalizing: false,
+            expected,
+            end,
+            identical,
+            state: ProgramState::AwaitData,
+        };
+        program.blocks.push(buf)?;
+        program.state = if program.select_sector(current_sector) {
+            program.erase(memory, 0)?
+        } else {
+            program.erase_or_program(memory, 0)?
+        };
+        Ok(program)
     }
+    /// Queues a block. Programming starts right away if flash is idle.
     fn update(&mut self, memory: &mut Memory, buf: &[u8]) -> Poll<usbd_dfu::Error> {
-        match &mut self.state {
-            ProgramState::AwaitData => {
-                let data_len = buf.len();
-                let mut data = [0; DFUModeImpl::TRANSFER_SIZE as usize];
-                data[..data_len].copy_from_slice(buf);
-
-                self.state = match Self::erase_or_program(
-                    memory,
-                    self.addr,
-                    &mut self.current_sector,
-                    data,
-                    data_len,
-                    0,
-                ) {
-                    Ok(state) => state,
-                    Err(e) => return Poll::Ready(e),
-                };
-                Poll::Pending
-            }
-            _ => Poll::Ready(usbd_dfu::Error::Unknown),
+        if self.finalizing {
+            return Poll::Ready(usbd_dfu::Error::Unknown);
+        }
+        if let Err(e) = self.blocks.push(buf) {
+            return Poll::Ready(e);
+        }
+        if let ProgramState::AwaitData = self.state {
+            self.state = match self.erase_or_program(memory, 0) {
+                Ok(state) => state,
+                Err(e) => return Poll::Ready(e),
+            };
         }
+        Poll::Pending
+    }
+    /// True when there is room for another block.
+    fn is_ready(&self) -> bool {
+        !self.blocks.is_full()
     }
     fn finalize(&mut self, memory: &mut Memory) -> Poll<usbd_dfu::Error> {
+        self.finalizing = true;
         match self.state {
             ProgramState::AwaitData => match self.erase_or_program_manifest(memory) {
                 Poll::Pending => Poll::Pending,
-                Poll::Ready(Ok(_)) => unreachable!(),
+                // the manifest is never written synchronously
+                Poll::Ready(Ok(_)) => Poll::Ready(usbd_dfu::Error::Unknown),
                 Poll::Ready(Err(e)) => Poll::Ready(e),
             },
+            // the manifest follows once the queued blocks are programmed
+            ProgramState::AwaitEraseBeforeProgram { .. }
+            | ProgramState::AwaitReplay { .. }
+            | ProgramState::AwaitProgram { .. } => Poll::Pending,
             _ => Poll::Ready(usbd_dfu::Error::Unknown),
         }
     }
     fn poll(&mut self, memory: &mut Memory) -> Poll<Result<()>> {
-        if let ProgramState::AwaitData = self.state {
-            return Poll::Pending;
+        match self.state {
+            ProgramState::AwaitData => return Poll::Pending,
+            ProgramState::Done => return Poll::Ready(Ok(())),
+            _ => {}
         }
 
         match memory.poll() {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Ready(Ok(n)) => match self.state {
-                ProgramState::AwaitData => unreachable!(),
-                ProgramState::AwaitEraseBeforeProgram {
-                    data,
-                    data_len,
-                    wr_ptr,
-                } => {
-                    match memory.program(self.addr, &data[wr_ptr..data_len]) {
-                        Poll::Ready(e) => return Poll::Ready(Err(e)),
-                        Poll::Pending => {}
+            Poll::Ready(Ok(n)) => {
+                let next = match self.state {
+                    // nothing was started in these states
+                    ProgramState::AwaitData | ProgramState::Done => {
+                        return Poll::Ready(Err(usbd_dfu::Error::Unknown))
                     }
-                    self.state = ProgramState::AwaitProgram {
-                        data,
-                        data_len,
-                        wr_ptr,
-                    };
-                    Poll::Pending
-                }
-                ProgramState::AwaitProgram {
-                    data,
-                    data_len,
-                    mut wr_ptr,
-                } => {
-                    self.addr += n;
-                    wr_ptr += n;
-
-                    assert!(wr_ptr <= data_len);
-                    self.state = if wr_ptr == data_len {
-                        ProgramState::AwaitData
-                    } else {
-                        match Self::erase_or_program(
-                            memory,
-                            self.addr,
-                            &mut self.current_sector,
-                            data,
-                            data_len,
-                            wr_ptr,
-                        ) {
-                            Err(e) => return Poll::Ready(Err(e)),
-                            Ok(state) => state,
-                        }
-                    };
-                    Poll::Pending
-                }
-                ProgramState::AwaitErase => self.erase_or_program_manifest(memory),
-                ProgramState::AwaitProgramManifest { data, mut wr_ptr } => {
-                    self.addr += n;
-                    wr_ptr += n;
-
-                    assert!(wr_ptr <= data.len());
-                    if wr_ptr == data.len() {
-                        self.state = ProgramState::Done;
-                        Poll::Ready(Ok(()))
-                    } else {
-                        self.state = ProgramState::AwaitProgramManifest { data, wr_ptr };
-                        match memory.program(self.addr, &data[wr_ptr..]) {
-                            Poll::Ready(e) => Poll::Ready(Err(e)),
-                            Poll::Pending => Poll::Pending,
-                        }
+                    ProgramState::AwaitEraseBeforeProgram { wr_ptr } => {
+                        self.sector_state = SectorState::Erased;
+                        self.replay_or_program(memory, wr_ptr, 0)
+                    }
+                    ProgramState::AwaitReplay { wr_ptr, replay_ptr } => {
+                        self.replay_or_program(memory, wr_ptr, replay_ptr + n)
+                    }
+                    ProgramState::AwaitProgram { mut wr_ptr } => {
+                        self.addr += n;
+                        wr_ptr += n;
+                        self.erase_or_program(memory, wr_ptr)
+                    }
+                    ProgramState::AwaitErase => return self.erase_or_program_manifest(memory),
+                    ProgramState::AwaitProgramManifest { data, mut wr_ptr } => {
+                        self.addr += n;
+                        wr_ptr += n;
+
+                        return match data.get(wr_ptr..) {
+                            Some([]) => {
+                                self.state = ProgramState::Done;
+                                Poll::Ready(Ok(()))
+                            }
+                            Some(rest) => {
+                                self.state = ProgramState::AwaitProgramManifest { data, wr_ptr };
+                                match memory.start_program(self.addr, rest) {
+                                    Poll::Ready(e) => Poll::Ready(Err(e)),
+                                    Poll::Pending => Poll::Pending,
+                                }
+                            }
+                            // more was programmed than requested
+                            None => Poll::Ready(Err(usbd_dfu::Error::Programming)),
+                        };
+                    }
+                };
+
+                match next {
+                    Err(e) => Poll::Ready(Err(e)),
+                    Ok(ProgramState::AwaitData) if self.finalizing => {
+                        self.erase_or_program_manifest(memory)
+                    }
+                    Ok(state) => {
+                        self.state = state;
+                        Poll::Pending
                     }
                 }
-                ProgramState::Done => unreachable!(),
-            },
+            }
         }
     }
 
-    fn erase_or_program(
-        memory: &mut Memory,
-        addr: usize,
-        current_sector: &mut Sector,
-        data: [u8; DFUModeImpl::TRANSFER_SIZE as usize],
-        data_len: usize,
-        wr_ptr: usize,
-    ) -> Result<ProgramState> {
-        if addr >= MANIFEST_REGION_START {
-            return Err(usbd_dfu::Error::Address);
+    /// Makes `sector` the current sector. Returns true if it has to be erased before anything can
+    /// be programmed in it.
+    fn select_sector(&mut self, sector: Sector) -> bool {
+        self.current_sector = sector;
+        if sector.is_erased() {
+            self.sector_state = SectorState::Erased;
+            false
+        } else if sector.size() <= REPLAY_LENGTH || self.identical {
+            self.sector_state = SectorState::InPlace;
+            false
+        } else {
+            true
         }
+    }
 
-        let sector = match Sector::try_from(addr) {
-            Ok(sector) => sector,
-            Err(e) => return Err(e),
-        };
+    fn erase(&mut self, memory: &mut Memory, wr_ptr: usize) -> Result<ProgramState> {
+        match memory.start_erase(self.current_sector) {
+            Poll::Ready(e) => return Err(e),
+            Poll::Pending => {}
+        }
+        Ok(ProgramState::AwaitEraseBeforeProgram { wr_ptr })
+    }
 
-        let state = if sector != *current_sector {
-            *current_sector = sector;
-            match memory.erase(sector) {
-                Poll::Ready(e) => return Err(e),
-                Poll::Pending => {}
+    /// Resumes programming the front block at `wr_ptr`, moving on to the next blocks as they get
+    /// completed.
+    fn erase_or_program(&mut self, memory: &mut Memory, mut wr_ptr: usize) -> Result<ProgramState> {
+        loop {
+            let data_len = match self.blocks.front() {
+                Some(block) => block.len,
+                None => return Ok(ProgramState::AwaitData),
+            };
+            if wr_ptr > data_len {
+                return Err(usbd_dfu::Error::Programming);
             }
-            ProgramState::AwaitEraseBeforeProgram {
-                data,
-                data_len,
-                wr_ptr,
+            if wr_ptr == data_len {
+                self.blocks.pop();
+                wr_ptr = 0;
+                continue;
             }
-        } else {
-            match memory.program(addr, &data[wr_ptr..data_len]) {
+            // past the announced length, or into the manifest region
+            if self.addr >= self.end {
+                return Err(usbd_dfu::Error::Address);
+            }
+
+            let sector = Sector::try_from(self.addr)?;
+            if sector != self.current_sector && self.select_sector(sector) {
+                return self.erase(memory, wr_ptr);
+            }
+
+            // a run never goes past the end of the current sector
+            let offset = self.addr - sector.start();
+            let len = usize::min(data_len - wr_ptr, sector.size() - offset);
+            let data = self
+                .blocks
+                .front()
+                .and_then(|block| block.data.get(wr_ptr..wr_ptr + len))
+                .ok_or(usbd_dfu::Error::Unknown)?;
+            if self.sector_state == SectorState::InPlace {
+                let old = unsafe { core::slice::from_raw_parts(self.addr as *const u8, len) };
+                // the start of a large sector is all that can be kept
+                let kept = usize::min(len, REPLAY_LENGTH.saturating_sub(offset));
+                let replay = unsafe { REPLAY.assume_init_mut().get_mut(offset..offset + kept) };
+                replay
+                    .ok_or(usbd_dfu::Error::Address)?
+                    .copy_from_slice(&data[..kept]);
+
+                let same = data
+                    .iter()
+                    .zip(old)
+                    .take_while(|(new, old)| new == old)
+                    .count();
+                if same != 0 {
+                    // already there, move on to what differs
+                    self.addr += same;
+                    wr_ptr += same;
+                    continue;
+                } else if data.iter().zip(old).any(|(new, old)| new & !old != 0) {
+                    // past `REPLAY_LENGTH` the image can only be the installed one, the data
+                    // received for this sector so far is lost once it is erased
+                    if offset > REPLAY_LENGTH {
+                        return Err(usbd_dfu::Error::Verify);
+                    }
+                    return self.erase(memory, wr_ptr);
+                }
+            }
+
+            match memory.start_program(self.addr, data) {
                 Poll::Pending => {}
                 Poll::Ready(e) => return Err(e),
             }
 
-            ProgramState::AwaitProgram {
-                data,
-                data_len,
-                wr_ptr,
-            }
-        };
-        Ok(state)
+            return Ok(ProgramState::AwaitProgram { wr_ptr });
+        }
+    }
+
+    /// Programs back what was received for the current sector before it got erased, then resumes
+    /// with the front block.
+    fn replay_or_program(
+        &mut self,
+        memory: &mut Memory,
+        wr_ptr: usize,
+        replay_ptr: usize,
+    ) -> Result<ProgramState> {
+        let start = self.current_sector.start();
+        let replay_len = self.addr - start;
+        if replay_ptr == replay_len {
+            return self.erase_or_program(memory, wr_ptr);
+        }
+
+        let replay = unsafe { REPLAY.assume_init_ref().get(replay_ptr..replay_len) };
+        let replay = replay.ok_or(usbd_dfu::Error::Programming)?;
+        match memory.start_program(start + replay_ptr, replay) {
+            Poll::Pending => {}
+            Poll::Ready(e) => return Err(e),
+        }
+        Ok(ProgramState::AwaitReplay { wr_ptr, replay_ptr })
     }
 
     fn erase_or_program_manifest(&mut self, memory: &mut Memory) -> Poll<Result<()>> {
-        match self.current_sector.next() {
+        // sectors past the end of the image are wiped unless they already are blank. The end of
+        // the sector holding the last byte of the image is left as is when it was updated in
+        // place: the manifest deliberately covers `image_length` bytes only, and neither the
+        // checks nor the uploads read past them.
+        let next = loop {
+            match self.current_sector.next() {
+                Some(sector) if sector.is_erased() => continue,
+                next => break next,
+            }
+        };
+        match next {
             Some(sector) => {
                 self.state = ProgramState::AwaitErase;
-                match memory.erase(sector) {
+                match memory.start_erase(sector) {
                     Poll::Ready(e) => Poll::Ready(Err(e)),
                     Poll::Pending => Poll::Pending,
                 }
             }
             None => {
                 let length = self.addr - APPLICATION_REGION_START;
-                let manifest = Manifest {
-                    length,
-                    hash: ApplicationRef::get_with_length(length).compute_hash(),
-                };
-                let manifest: [u8; core::mem::size_of::<Manifest>()] =
-                    unsafe { core::mem::transmute(manifest) };
+                let app = ApplicationRef::get_with_length(length);
+                let mut manifest = Manifest::new(
+                    ImageType::Application,
+                    APPLICATION_REGION_START as u32,
+                    length as u32,
+                    ImageHeader::security_version_of(app.0),
+                    HASH_ALGORITHM,
+                    &app.compute_hash(),
+                );
+                if self.expected.hash() != manifest.hash() {
+                    return Poll::Ready(Err(usbd_dfu::Error::Verify));
+                }
+                // flags are the only thing the device can't tell by itself
+                manifest.flags = self.expected.flags;
+                if self.expected != manifest {
+                    return Poll::Ready(Err(usbd_dfu::Error::File));
+                }
+                let manifest = manifest.to_bytes();
 
-                match memory.program(MANIFEST_REGION_START, &manifest[..]) {
+                match memory.start_program(MANIFEST_REGION_START, &manifest[..]) {
                     Poll::Ready(e) => return Poll::Ready(Err(e)),
                     Poll::Pending => {}
                 }
@@ -275,11 +472,50 @@ impl Program {
     }
 }
 
+/// True if `manifest` is the one of the installed image and that image still matches its hash, so
+/// an earlier download that was cut short doesn't count.
+fn is_installed(manifest: &Manifest) -> bool {
+    match installed_manifest() {
+        Ok(installed) if installed == *manifest => {
+            let app = ApplicationRef::get_with_length(installed.image_length as usize);
+            app.compute_hash()[..] == *installed.hash()
+        }
+        _ => false,
+    }
+}
+
+/// Splits the manifest, and its signature, off the first block of an image built by the host.
+/// Raw images are refused: nothing tells how long they are before the flash is erased, and the
+/// DFU suffix never reaches the device.
+fn split_manifest(buf: &[u8]) -> Result<(Manifest, &[u8])> {
+    if !buf.starts_with(&MANIFEST_MAGIC.to_le_bytes()) {
+        return Err(usbd_dfu::Error::File);
+    }
+
+    let bytes = buf
+        .get(..MANIFEST_LENGTH)
+        .and_then(|bytes| <&[u8; MANIFEST_LENGTH]>::try_from(bytes).ok())
+        .ok_or(usbd_dfu::Error::File)?;
+    let manifest = Manifest::from_bytes(bytes).map_err(|_| usbd_dfu::Error::File)?;
+    // the signature isn't checked yet
+    let image = buf
+        .get(manifest.encoded_length()..)
+        .ok_or(usbd_dfu::Error::File)?;
+    Ok((manifest, image))
+}
+
+/// Alternate settings of the DFU interface.
+const FIRMWARE: u8 = 0;
+const DIAGNOSTICS: u8 = 1;
+
 #[derive(Debug)]
 enum DFUModeState {
     Download(Program),
     Manifetation(Program),
-    Upload(&'static [u8]),
+    /// Offset of the next block of the installed image, counted from the start of its manifest.
+    Upload(usize),
+    /// Offset of the next block of diagnostics.
+    UploadDiagnostics(usize),
     Idle,
     Error,
 }
@@ -288,40 +524,134 @@ pub struct DFUModeImpl {
     state: DFUModeState,
     memory: Memory,
     boot_mode: PC13<Input<Floating>>,
+    /// The application got stuck and was reset by the watchdog, it is not started again.
+    watchdog_reset: bool,
+    /// Alternate setting selected by the host, one of `FIRMWARE` or `DIAGNOSTICS`.
+    target: u8,
 }
 impl DFUModeImpl {
-    pub fn new(memory: Memory, boot_mode: PC13<Input<Floating>>) -> Self {
+    pub fn new(memory: Memory, boot_mode: PC13<Input<Floating>>, watchdog_reset: bool) -> Self {
         Self {
             state: DFUModeState::Idle,
             memory,
             boot_mode,
+            watchdog_reset,
+            target: FIRMWARE,
         }
     }
 }
 
+impl DFUModeImpl {
+    /// The last block of a download is written, whether `poll` or the host's
+    /// GETSTATUS noticed it first.
+    fn manifested(&mut self) {
+        // the new image gets a fresh set of attempts
+        boot_attempts::new_image();
+        self.state = DFUModeState::Idle;
+    }
+
+    /// Raises the minimum security version to the one of the installed image once the application
+    /// confirmed it, so the images older than it can no longer be installed. Only meant for an
+    /// image `is_firmware_valid` accepted: a download resets the confirmation, the image that sets
+    /// it again is the installed one.
+    pub async fn commit_security_version(&mut self) -> Result<()> {
+        if !boot_attempts::is_confirmed() {
+            return Ok(());
+        }
+        let manifest = installed_manifest().map_err(|_| usbd_dfu::Error::Firmware)?;
+        let res = raise_minimum_security_version(&mut self.memory, manifest.security_version).await;
+        dbgprint!("security version committed: {:?}", res);
+        res
+    }
+
+    fn upload_diagnostics(&mut self, block_number: u16, buf: &mut [u8]) -> Result<usize> {
+        let offset = match self.state {
+            DFUModeState::UploadDiagnostics(offset) if block_number != 0 => offset,
+            // the first block (re)starts the upload
+            DFUModeState::Idle | DFUModeState::UploadDiagnostics(_) => 0,
+            _ => return Err(usbd_dfu::Error::Unknown),
+        };
+
+        let size = crate::trace::read_diagnostics(offset, buf);
+
+        // a short block ends the upload
+        self.state = if size == buf.len() {
+            DFUModeState::UploadDiagnostics(offset + size)
+        } else {
+            DFUModeState::Idle
+        };
+        Ok(size)
+    }
+}
+
 impl_capabilities!(DFUModeImpl);
 impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
     const POLL_TIMEOUT: u32 = 10;
+    const TARGETS: &'static [&'static str] = &["Firmware", "Diagnostics"];
+    const VENDOR_ERROR: Option<&'static str> = Some("Watchdog reset");
+
+    fn select_target(&mut self, alt_setting: u8) -> Result<()> {
+        self.target = alt_setting;
+        Ok(())
+    }
+
+    fn poll_timeout(&mut self) -> u32 {
+        self.memory.busy_time().unwrap_or(Self::POLL_TIMEOUT)
+    }
+
+    fn startup_error(&mut self) -> Option<usbd_dfu::Error> {
+        // DFU has no status for watchdog resets, it is told apart by its description
+        if self.watchdog_reset {
+            Some(usbd_dfu::Error::Vendor)
+        } else {
+            None
+        }
+    }
 
     fn is_firmware_valid(&mut self) -> bool {
-        let manifest = super::Manifest::get();
+        let manifest = installed_manifest();
+
+        dbgprint!("{:?}", &manifest);
+        // written by another bootloader, or nothing was ever installed
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(_) => return false,
+        };
+        let is_for_this_device = manifest.image_type == ImageType::Application
+            && manifest.load_address == APPLICATION_REGION_START as u32
+            && manifest.image_length as usize <= APPLICATION_LENGTH
+            && manifest.hash_algorithm == HASH_ALGORITHM;
+        if !is_for_this_device {
+            return false;
+        }
 
-        dbgprint!("{:x?}\r\n", &manifest);
+        if self.watchdog_reset {
+            return false;
+        }
+
+        // the application kept failing before reporting itself as healthy
+        if boot_attempts::get() >= boot_attempts::LIMIT {
+            return false;
+        }
 
         use embedded_hal::digital::v2::InputPin;
         if self.boot_mode.is_low().unwrap_or_else(|_| unreachable!()) {
             return false;
         }
 
-        let app = ApplicationRef::get();
-        let is_hash_valid = app.compute_hash() == manifest.hash;
+        if manifest.security_version < minimum_security_version() {
+            return false;
+        }
+
+        let app = ApplicationRef::get_with_length(manifest.image_length as usize);
+        let is_hash_valid = app.compute_hash()[..] == *manifest.hash();
 
         if is_hash_valid {
             let (sp, reset) = unsafe {
                 let ptr = APPLICATION_REGION_START as *const u32;
                 (*ptr, *ptr.offset(1))
             };
-            dbgprint!("{:x} {:x}\r\n", sp, reset);
+            dbgprint!("{:x} {:x}", sp, reset);
             (0x2000_0000..0x2002_0000).contains(&sp)
                 && (APPLICATION_REGION_START..MANIFEST_REGION_START).contains(&(reset as usize))
         } else {
@@ -330,17 +660,14 @@ impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
     }
     fn is_transfer_complete(&mut self) -> Result<bool> {
         if let DFUModeState::Download(program) = &self.state {
-            if let ProgramState::AwaitData = program.state {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+            // keep the host waiting while an erase is about to start
+            Ok(program.is_ready() && self.memory.busy_time().is_none())
         } else {
             Err(usbd_dfu::Error::Unknown)
         }
     }
     fn is_manifestation_in_progress(&mut self) -> Result<bool> {
-        dbgprint!("update manifest\r\n");
+        dbgprint!("update manifest");
         let res = match &mut self.state {
             DFUModeState::Download(program) => match program.finalize(&mut self.memory) {
                 Poll::Ready(e) => Err(e),
@@ -351,7 +678,7 @@ impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
             },
             DFUModeState::Manifetation(program) => match program.poll(&mut self.memory) {
                 Poll::Ready(Ok(())) => {
-                    self.state = DFUModeState::Idle;
+                    self.manifested();
                     Ok(false)
                 }
                 Poll::Ready(Err(e)) => Err(e),
@@ -367,12 +694,12 @@ impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
     }
 
     fn poll(&mut self) -> Result<()> {
-        //dbgprint!("{:x?}\r\n", &self.state);
+        //dbgprint!("{:?}", &self.state);
         match &mut self.state {
             DFUModeState::Download(program) | DFUModeState::Manifetation(program) => {
                 match program.poll(&mut self.memory) {
                     Poll::Pending => {}
-                    Poll::Ready(Ok(())) => self.state = DFUModeState::Idle,
+                    Poll::Ready(Ok(())) => self.manifested(),
                     Poll::Ready(Err(e)) => {
                         self.state = DFUModeState::Error;
                         return Err(e);
@@ -384,34 +711,65 @@ impl usbd_dfu::mode::DeviceFirmwareUpgrade for DFUModeImpl {
         Ok(())
     }
 
-    fn upload(&mut self, _block_number: u16, buf: &mut [u8]) -> Result<usize> {
+    fn upload(&mut self, block_number: u16, buf: &mut [u8]) -> Result<usize> {
         //dbgprint!(
-        //    "{:?} {} {}\r\n",
+        //    "{:?} {} {}",
         //    self.upload_ptr.map(|slice| slice.len()),
         //    block_number,
         //    buf.len()
         //);
 
-        if let DFUModeState::Idle = self.state {
-            self.state = DFUModeState::Upload(ApplicationRef::get().0);
+        if self.target == DIAGNOSTICS {
+            return self.upload_diagnostics(block_number, buf);
         }
-        let app_slice = match &mut self.state {
-            DFUModeState::Upload(state) => state,
+
+        let offset = match self.state {
+            DFUModeState::Idle => 0,
+            DFUModeState::Upload(offset) => offset,
             _ => return Err(usbd_dfu::Error::Unknown),
         };
 
-        let size = usize::min(buf.len(), app_slice.len());
-        buf[..size].copy_from_slice(&app_slice[..size]);
-        if size != 0 {
-            *app_slice = &app_slice[size..];
-        } else {
-            self.state = DFUModeState::Idle;
+        // the image goes out the way it was downloaded, manifest first, so it can be downloaded
+        // again. The signature is not kept on the device: the upload is an unsigned image.
+        let mut size = 0;
+        if let Ok(mut manifest) = installed_manifest() {
+            manifest.flags &= !FLAG_SIGNED;
+            let app = ApplicationRef::get_with_length(manifest.image_length as usize);
+            let manifest = manifest.to_bytes();
+
+            let mut start = offset;
+            for part in [&manifest[..], app.0].iter() {
+                if start >= part.len() {
+                    start -= part.len();
+                    continue;
+                }
+                let length = usize::min(buf.len() - size, part.len() - start);
+                buf[size..size + length].copy_from_slice(&part[start..start + length]);
+                size += length;
+                start = 0;
+            }
         }
 
+        self.state = if size != 0 {
+            DFUModeState::Upload(offset + size)
+        } else {
+            DFUModeState::Idle
+        };
+
         Ok(size)
     }
+    fn abort(&mut self) {
+        // a partial image never matches the installed manifest, it is not started
+        self.state = DFUModeState::Idle;
+    }
+
     fn download(&mut self, _block_number: u16, buf: &[u8]) -> Result<()> {
-        dbgprint!("{}-{}\r\n", _block_number, buf.len());
+        dbgprint!("{}-{}", _block_number, buf.len());
+
+        if self.target == DIAGNOSTICS {
+            // read-only target
+            return Err(usbd_dfu::Error::Target);
+        }
 
         let res = match &mut self.state {
             DFUModeState::Idle => {
@@ -15,29 +15,56 @@
 use core::mem::MaybeUninit;
 use cortex_m::peripheral::syst::SystClkSource;
 use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+use stm32f4xx_hal::pac::{interrupt, Interrupt};
 use stm32f4xx_hal::prelude::*;
 
 #[cfg(feature = "bootloader")]
 use dfu::mode::DFUModeImpl as DFUImpl;
 #[cfg(feature = "application")]
 use dfu::runtime::DFURuntimeImpl as DFUImpl;
-use dfu::Manifest;
+use dfu_image::MANIFEST_LENGTH;
 
 const FLASH_END: usize = 0x0808_0000;
-const MANIFEST_SIZE_ALIGNED: usize = ((core::mem::size_of::<Manifest>() + 127) / 128) * 128;
+const MANIFEST_SIZE_ALIGNED: usize = ((MANIFEST_LENGTH + 127) / 128) * 128;
 const MANIFEST_REGION_START: usize = FLASH_END - MANIFEST_SIZE_ALIGNED;
 
 static mut EP_MEMORY: MaybeUninit<[u32; 256]> = MaybeUninit::uninit();
 
-#[cfg(feature = "debug-buffer")]
+#[cfg(any(feature = "debug-buffer", feature = "debug-cdc", feature = "debug-rtt"))]
 static mut DEBUG_BUFFER: MaybeUninit<[u8; 1024]> = MaybeUninit::uninit();
-#[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-pub static mut WRITER: Option<debug::DbgWriter> = None;
+#[cfg(feature = "debug-uart")]
+type DebugUart = stm32f4xx_hal::serial::Serial<
+    stm32f4xx_hal::pac::USART2,
+    (
+        stm32f4xx_hal::gpio::gpioa::PA2<stm32f4xx_hal::gpio::Alternate<stm32f4xx_hal::gpio::AF7>>,
+        stm32f4xx_hal::serial::NoRx,
+    ),
+>;
+#[cfg(feature = "debug-uart")]
+static mut DEBUG_UART: Option<crate::debug::Uart<DebugUart>> = None;
 
 pub fn reset() -> ! {
     stm32f4xx_hal::stm32::SCB::sys_reset()
 }
 
+#[cortex_m_rt::exception]
+fn SysTick() {
+    crate::executor::tick();
+}
+
+#[interrupt]
+fn OTG_FS() {
+    // the cause is only cleared by `UsbDevice::poll`, keep the line masked until then
+    cortex_m::peripheral::NVIC::mask(Interrupt::OTG_FS);
+    crate::executor::wake();
+}
+
+/// Sleeps until USB or the SysTick need attention.
+pub async fn wait_for_event() {
+    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::OTG_FS) };
+    crate::executor::wait_for_event().await
+}
+
 pub fn init() -> (
     usb_device::bus::UsbBusAllocator<impl usb_device::class_prelude::UsbBus>,
     stm32f4xx_hal::gpio::gpioa::PA5<stm32f4xx_hal::gpio::Output<stm32f4xx_hal::gpio::PushPull>>,
@@ -47,6 +74,11 @@ pub fn init() -> (
     let dp = stm32f4xx_hal::stm32::Peripherals::take().unwrap_or_else(|| unreachable!());
     let mut cp = cortex_m::Peripherals::take().unwrap_or_else(|| unreachable!());
 
+    #[cfg_attr(not(feature = "bootloader"), allow(unused_variables))]
+    let watchdog_reset = watchdog::caused_reset(&dp.RCC);
+    watchdog::start(&dp.IWDG);
+    boot_attempts::unlock(&dp.RCC, &dp.PWR);
+
     let rcc = dp.RCC.constrain();
 
     dp.FLASH.acr.modify(|_, w| {
@@ -65,6 +97,7 @@ pub fn init() -> (
     cp.SYST.set_reload(clocks.sysclk().0 / (8 * 1_000));
     cp.SYST.clear_current();
     cp.SYST.enable_counter();
+    cp.SYST.enable_interrupt();
 
     let gpioa = dp.GPIOA.split();
     let led = gpioa.pa5.into_push_pull_output();
@@ -80,34 +113,31 @@ pub fn init() -> (
         hclk: clocks.hclk(),
     };
 
-    #[cfg(feature = "debug-buffer")]
-    cortex_m::interrupt::free(move |_| unsafe {
-        WRITER = Some(debug::DbgWriter::using_buffer(
-            DEBUG_BUFFER.assume_init_mut(),
-        ));
-    });
+    #[cfg(any(feature = "debug-buffer", feature = "debug-cdc", feature = "debug-rtt"))]
+    crate::debug::install_buffered(unsafe { DEBUG_BUFFER.assume_init_mut() });
     #[cfg(feature = "debug-uart")]
     {
         let pa2 = gpioa.pa2.into_alternate_af7();
-        let usart2 = dp.USART2;
-        cortex_m::interrupt::free(move |_| unsafe {
-            let config = stm32f4xx_hal::serial::config::Config::default().baudrate(115200.bps());
-            let serial = stm32f4xx_hal::serial::Serial::new(
-                usart2,
-                (pa2, stm32f4xx_hal::serial::NoRx),
-                config,
-                clocks,
-            )
-            .unwrap_or_else(|_| unreachable!());
-
-            WRITER = Some(serial);
-        });
+        let config = stm32f4xx_hal::serial::config::Config::default().baudrate(115200.bps());
+        let serial = stm32f4xx_hal::serial::Serial::new(
+            dp.USART2,
+            (pa2, stm32f4xx_hal::serial::NoRx),
+            config,
+            clocks,
+        )
+        .unwrap_or_else(|_| unreachable!());
+
+        crate::debug::install(unsafe { DEBUG_UART.get_or_insert(crate::debug::Uart(serial)) });
     }
 
     #[cfg(feature = "application")]
-    let dfu = DFUImpl;
+    let dfu = DFUImpl::new();
     #[cfg(feature = "bootloader")]
-    let dfu = DFUImpl::new(bootloader::Memory::new(dp.FLASH), boot_mode);
+    let dfu = DFUImpl::new(
+        bootloader::Memory::new(dp.FLASH, bootloader::VoltageRange::Range3),
+        boot_mode,
+        watchdog_reset,
+    );
 
     (
         UsbBus::new(usb, unsafe { EP_MEMORY.assume_init_mut() }),
@@ -118,71 +148,81 @@ pub fn init() -> (
 }
 
 pub async fn trigger<T>(_: &mut T) {}
-#[cfg(feature = "debug-uart")]
-mod debug {
-    use stm32f4xx_hal::gpio::{gpioa::PA2, Alternate, AF7};
-    use stm32f4xx_hal::pac;
-    use stm32f4xx_hal::serial;
+pub mod watchdog {
+    use stm32f4xx_hal::pac::{IWDG, RCC};
+
+    /// Starts the independent watchdog with a 4s timeout. Once started it can't be stopped, so the
+    /// application started by the bootloader has to feed it too.
+    pub fn start(iwdg: &IWDG) {
+        // LSI runs at 32kHz: 32kHz / 64 = 500Hz, 2000 ticks make 4s
+        iwdg.kr.write(|w| unsafe { w.key().bits(0x5555) });
+        iwdg.pr.write(|w| unsafe { w.pr().bits(0b100) });
+        iwdg.rlr.write(|w| unsafe { w.rl().bits(2000) });
+        iwdg.kr.write(|w| unsafe { w.key().bits(0xCCCC) });
+    }
+
+    pub fn feed() {
+        let iwdg = unsafe { &*IWDG::ptr() };
+        iwdg.kr.write(|w| unsafe { w.key().bits(0xAAAA) });
+    }
 
-    pub type DbgWriter = serial::Serial<pac::USART2, (PA2<Alternate<AF7>>, serial::NoRx)>;
+    /// True if the last reset was triggered by the watchdog. Clears the reset flags.
+    pub fn caused_reset(rcc: &RCC) -> bool {
+        let res = rcc.csr.read().wdgrstf().bit_is_set();
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        res
+    }
 }
 
-#[cfg(feature = "debug-buffer")]
-pub mod debug {
-    pub struct DbgWriter {
-        buffer: &'static mut [u8],
-        len: usize,
+/// Number of times the bootloader started the application without the application reporting
+/// itself as healthy, and whether the installed image ever did. It is kept in RTC backup registers
+/// so it survives resets, but not a loss of power.
+pub mod boot_attempts {
+    use stm32f4xx_hal::pac::{PWR, RCC};
+
+    /// Number of attempts after which the bootloader no longer starts the application.
+    pub const LIMIT: u32 = 3;
+
+    const RTC_BKP0R: *mut u32 = 0x4000_2850 as *mut u32;
+    const RTC_BKP1R: *mut u32 = 0x4000_2854 as *mut u32;
+    const CONFIRMED: u32 = 0xC0FF_1A4D;
+
+    /// Enables write access to the backup domain.
+    pub fn unlock(rcc: &RCC, pwr: &PWR) {
+        rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
     }
-    impl DbgWriter {
-        pub fn using_buffer(buffer: &'static mut [u8]) -> Self {
-            Self { buffer, len: 0 }
-        }
+
+    pub fn get() -> u32 {
+        unsafe { core::ptr::read_volatile(RTC_BKP0R) }
     }
 
-    impl ::core::fmt::Write for DbgWriter {
-        fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
-            if self.len > self.buffer.len() {
-                // invalid state, that sucks
-                self.len = 0;
-            }
-            let len = core::cmp::min(self.buffer.len() - self.len, s.len());
-            let from = self.len;
-            self.len += len;
-            self.buffer[from..self.len].copy_from_slice(&s.as_bytes()[..len]);
-            Ok(())
-        }
+    pub fn increment() {
+        unsafe { core::ptr::write_volatile(RTC_BKP0R, get().saturating_add(1)) }
     }
-    pub fn consume_debug(mut reader: impl FnMut(&[u8]) -> usize) {
-        cortex_m::interrupt::free(|_| unsafe {
-            if let Some(w) = super::WRITER.as_mut() {
-                let len = w.len;
-                w.len -= reader(&w.buffer[..len]);
-                w.buffer.copy_within(w.len..len, 0);
-            };
-        });
+
+    /// Called by the application once it is up and running. This confirms the installed image.
+    pub fn clear() {
+        unsafe {
+            core::ptr::write_volatile(RTC_BKP0R, 0);
+            core::ptr::write_volatile(RTC_BKP1R, CONFIRMED);
+        }
     }
-}
 
-#[macro_export]
-#[cfg(any(feature = "debug-uart", feature = "debug-buffer"))]
-macro_rules! dbgprint {
-    ($($arg:tt)*) => {
-        {
-            use cortex_m::interrupt::free as interrupt_free;
-            #[allow(unused_unsafe)]
-            interrupt_free(|_| unsafe {
-                use ::core::fmt::Write;
-                use $crate::nucleo_f401re::WRITER;
-                WRITER.as_mut().map(|w| w.write_fmt(format_args!($($arg)*)));
-            });
+    /// Gives a freshly installed image a full set of attempts. It is unconfirmed until it calls
+    /// `clear`.
+    pub fn new_image() {
+        unsafe {
+            core::ptr::write_volatile(RTC_BKP0R, 0);
+            core::ptr::write_volatile(RTC_BKP1R, 0);
         }
-    };
-}
+    }
 
-#[macro_export]
-#[cfg(not(any(feature = "debug-uart", feature = "debug-buffer")))]
-macro_rules! dbgprint {
-    ($($arg:tt)*) => {};
+    /// True if the installed image reported itself as healthy since it was installed or since
+    /// power-on.
+    pub fn is_confirmed() -> bool {
+        unsafe { core::ptr::read_volatile(RTC_BKP1R) == CONFIRMED }
+    }
 }
 
 mod dfu;
@@ -190,4 +230,4 @@ mod dfu;
 #[cfg(feature = "bootloader")]
 mod bootloader;
 #[cfg(feature = "bootloader")]
-pub use bootloader::jump_to_application;
+pub use bootloader::{erase_ended, jump_to_application};
@@ -1,46 +1,303 @@
 use crate::platform;
-use cortex_m_rt::exception;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m_rt::{exception, ExceptionFrame};
+use dfu_image::crc32;
 
-#[link_section = ".uninit"]
-pub static mut ERROR: core::mem::MaybeUninit<LastPanicMessage> = core::mem::MaybeUninit::uninit();
+const CRASH_RECORD_MAGIC: u32 = 0xC4A5_4ED0;
+const MESSAGE_LENGTH: usize = 256;
+const FILE_LENGTH: usize = 64;
+const STACK_SNAPSHOT_LENGTH: usize = 16;
 
-pub struct LastPanicMessage {
-    pub len: usize,
-    pub buffer: [u8; 1024],
+/// Length of a serialised record, see `CrashRecord::to_bytes`.
+pub const CRASH_RECORD_LENGTH: usize =
+    10 * 4 + MESSAGE_LENGTH + FILE_LENGTH + 12 * 4 + 4 * STACK_SNAPSHOT_LENGTH;
+
+/// Kept in its own RAM region, see the memory scripts, so neither the reset following a crash nor
+/// the other image's startup wipes it.
+#[link_section = ".crash_record"]
+static mut RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+/// Set by the fault handlers when they leave the rest of the record to `on_panic`.
+static FAULT_RECORDED: AtomicBool = AtomicBool::new(false);
+
+/// State of the core when a fault was taken.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub frame: ExceptionFrame,
+    /// Configurable Fault Status Register
+    pub cfsr: u32,
+    /// HardFault Status Register
+    pub hfsr: u32,
+    /// MemManage Fault Address Register
+    pub mmfar: u32,
+    /// BusFault Address Register
+    pub bfar: u32,
+}
+
+/// Description of the last crash. It survives `platform::reset()` but not a loss of power.
+///
+/// Only made of words and bytes, so whatever RAM holds after a power-on is a record, though not a
+/// sealed one.
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    /// CRC-32 of everything following this field.
+    crc: u32,
+    /// Number of crashes since the record was last cleared.
+    pub reset_count: u32,
+    message_len: u32,
+    message: [u8; MESSAGE_LENGTH],
+    file_len: u32,
+    file: [u8; FILE_LENGTH],
+    pub line: u32,
+    pub column: u32,
+    /// 1 when the crash comes from a fault exception, `fault` is meaningless otherwise.
+    fault_present: u32,
+    fault: Fault,
+    /// Stack pointer at the time of the crash.
+    pub sp: u32,
+    stack_len: u32,
+    stack: [u32; STACK_SNAPSHOT_LENGTH],
+}
+impl CrashRecord {
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or_default()
+    }
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or_default()
+    }
+    /// The first words found on the stack, from `sp` upward.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_len as usize]
+    }
+
+    /// Only set when the crash comes from a fault exception.
+    pub fn fault(&self) -> Option<&Fault> {
+        if self.fault_present == 1 {
+            Some(&self.fault)
+        } else {
+            None
+        }
+    }
+
+    /// The record for hosts to decode: its fields in order, every word in little-endian, the fault
+    /// following the word telling whether it is present. The CRC covers the bytes after it.
+    pub fn to_bytes(&self) -> [u8; CRASH_RECORD_LENGTH] {
+        let fault = &self.fault;
+        let frame = &fault.frame;
+        let mut bytes = [0; CRASH_RECORD_LENGTH];
+        let mut writer = Serializer {
+            bytes: &mut bytes,
+            len: 0,
+        };
+        writer.words(&[self.magic, self.crc, self.reset_count, self.message_len]);
+        writer.bytes(&self.message);
+        writer.words(&[self.file_len]);
+        writer.bytes(&self.file);
+        writer.words(&[self.line, self.column, self.fault_present]);
+        writer.words(&[frame.r0(), frame.r1(), frame.r2(), frame.r3()]);
+        writer.words(&[frame.r12(), frame.lr(), frame.pc(), frame.xpsr()]);
+        writer.words(&[fault.cfsr, fault.hfsr, fault.mmfar, fault.bfar]);
+        writer.words(&[self.sp, self.stack_len]);
+        writer.words(&self.stack);
+        debug_assert_eq!(writer.len, CRASH_RECORD_LENGTH);
+        bytes
+    }
+
+    fn compute_crc(&self) -> u32 {
+        crc32(&self.to_bytes()[8..])
+    }
+    fn seal(&mut self) {
+        self.magic = CRASH_RECORD_MAGIC;
+        self.crc = self.compute_crc();
+    }
+}
+
+/// Appends fields to a serialised record.
+struct Serializer<'a> {
+    bytes: &'a mut [u8; CRASH_RECORD_LENGTH],
+    len: usize,
+}
+impl Serializer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+    fn words(&mut self, words: &[u32]) {
+        for word in words {
+            self.bytes(&word.to_le_bytes());
+        }
+    }
 }
-impl core::fmt::Write for LastPanicMessage {
+
+/// Returns the record left by the last crash, if any.
+pub fn last_crash() -> Option<&'static CrashRecord> {
+    unsafe {
+        let record = &*RECORD.as_ptr();
+        let is_valid = record.magic == CRASH_RECORD_MAGIC && record.crc == record.compute_crc();
+        if is_valid {
+            Some(record)
+        } else {
+            None
+        }
+    }
+}
+
+/// Copies the diagnostics, starting at `offset`, to `buf`: the last crash record if any, recognisable
+/// by its leading magic number, followed by the pending debug output. Returns the number of bytes
+/// written.
+pub fn read_diagnostics(offset: usize, buf: &mut [u8]) -> usize {
+    crate::debug::with_pending(|[log, wrapped_log]| {
+        let record = last_crash().map(CrashRecord::to_bytes);
+        let record = record.as_ref().map_or(&[][..], |bytes| &bytes[..]);
+        copy_parts(&[record, log, wrapped_log], offset, buf)
+    })
+}
+
+/// Copies the concatenation of `parts`, starting at `offset`, to `buf`.
+fn copy_parts(parts: &[&[u8]], offset: usize, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    let mut offset = offset;
+    for part in parts {
+        if offset >= part.len() {
+            offset -= part.len();
+            continue;
+        }
+        let part = &part[offset..];
+        offset = 0;
+
+        let size = core::cmp::min(part.len(), buf.len() - len);
+        buf[len..len + size].copy_from_slice(&part[..size]);
+        len += size;
+    }
+    len
+}
+
+/// Forgets the last crash.
+pub fn clear() {
+    unsafe { (*RECORD.as_mut_ptr()).magic = 0 };
+}
+
+/// Gets the record ready for a new crash, keeping the count of the previous ones.
+fn start_record() -> &'static mut CrashRecord {
+    let reset_count = last_crash().map_or(0, |record| record.reset_count);
+    unsafe {
+        let record = &mut *RECORD.as_mut_ptr();
+        record.magic = 0;
+        record.reset_count = reset_count.wrapping_add(1);
+        record.message_len = 0;
+        record.file_len = 0;
+        record.line = 0;
+        record.column = 0;
+        record.fault_present = 0;
+        record.stack_len = 0;
+        record
+    }
+}
+
+/// Copies the stack content from `sp` up to the top of the stack.
+fn snapshot_stack(record: &mut CrashRecord, sp: u32) {
+    extern "C" {
+        static _stack_start: u32;
+    }
+    let stack_start = unsafe { &_stack_start as *const u32 as u32 };
+
+    record.sp = sp;
+    let available = (stack_start.saturating_sub(sp) / 4) as usize;
+    let len = core::cmp::min(available, STACK_SNAPSHOT_LENGTH);
+    for (i, word) in record.stack[..len].iter_mut().enumerate() {
+        *word = unsafe { core::ptr::read_volatile((sp as *const u32).add(i)) };
+    }
+    record.stack_len = len as u32;
+}
+
+/// Writes as much as fits in `buffer`, cutting on a character boundary.
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: &'a mut u32,
+}
+impl core::fmt::Write for Truncating<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let len = core::cmp::min(s.len(), self.buffer.len());
-        let start = self.len;
-        let end = start + len;
-        self.buffer[start..end].copy_from_slice(&s.as_bytes()[..len]);
-        self.len = end;
+        let start = *self.len as usize;
+        let mut len = core::cmp::min(s.len(), self.buffer.len() - start);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buffer[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        *self.len += len as u32;
         Ok(())
     }
 }
 
 #[panic_handler]
-fn on_panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe {
-        use core::fmt::Write;
-        let err = ERROR.assume_init_mut();
-        let _ = write!(err, "Woops that's a hard one");
-        platform::reset();
+fn on_panic(info: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let record = if FAULT_RECORDED.load(Ordering::Relaxed) {
+        unsafe { &mut *RECORD.as_mut_ptr() }
+    } else {
+        let record = start_record();
+        snapshot_stack(record, cortex_m::register::msp::read());
+        record
+    };
+
+    if let Some(message) = info.message() {
+        let _ = Truncating {
+            buffer: &mut record.message,
+            len: &mut record.message_len,
+        }
+        .write_fmt(*message);
+    }
+    if let Some(location) = info.location() {
+        let _ = Truncating {
+            buffer: &mut record.file,
+            len: &mut record.file_len,
+        }
+        .write_str(location.file());
+        record.line = location.line();
+        record.column = location.column();
     }
+    record.seal();
+
+    platform::reset();
 }
 
-#[cfg(not(feature = "bootloader"))]
-#[exception]
-#[allow(non_snake_case)]
-fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("Hardfault: {:#?}", ef)
+/// Stack pointer of the interrupted code: the stacked frame is followed by the floating point
+/// registers when EXC_RETURN bit 4 is clear (S0 to S15, FPSCR and a reserved word), then by a
+/// padding word when the stacked xPSR bit 9 is set, the core having aligned SP on 8 bytes.
+fn stack_before_exception(ef: &ExceptionFrame, exc_return: u32) -> u32 {
+    let mut sp = ef as *const ExceptionFrame as u32 + core::mem::size_of::<ExceptionFrame>() as u32;
+    if exc_return & (1 << 4) == 0 {
+        sp += 18 * 4;
+    }
+    if ef.xpsr() & (1 << 9) != 0 {
+        sp += 4;
+    }
+    sp
 }
 
-#[cfg(feature = "bootloader")]
 #[exception]
 #[allow(non_snake_case)]
-fn HardFault(_ef: &cortex_m_rt::ExceptionFrame) -> ! {
-    panic!("Hardfault");
+fn HardFault(ef: &ExceptionFrame) -> ! {
+    // the runtime's trampoline branches here, LR still holds EXC_RETURN until the first call
+    let exc_return = cortex_m::register::lr::read();
+    let scb = unsafe { &*cortex_m::peripheral::SCB::ptr() };
+
+    let record = start_record();
+    record.fault = Fault {
+        frame: *ef,
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        mmfar: scb.mmfar.read(),
+        bfar: scb.bfar.read(),
+    };
+    record.fault_present = 1;
+    snapshot_stack(record, stack_before_exception(ef, exc_return));
+    FAULT_RECORDED.store(true, Ordering::Relaxed);
+
+    panic!("HardFault");
 }
 
 #[exception]
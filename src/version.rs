@@ -0,0 +1,36 @@
+//! Identity of the firmware being built, see `build.rs`.
+
+use usbd_dfu::runtime::FirmwareInfo;
+
+pub const VERSION: [u16; 3] = [
+    parse(env!("CARGO_PKG_VERSION_MAJOR")) as u16,
+    parse(env!("CARGO_PKG_VERSION_MINOR")) as u16,
+    parse(env!("CARGO_PKG_VERSION_PATCH")) as u16,
+];
+pub const GIT_HASH: &str = env!("GIT_HASH");
+/// Seconds since the Unix epoch.
+pub const BUILD_TIME: u64 = parse(env!("BUILD_TIME"));
+/// Raise it along with a release that fixes a vulnerability: once that release is confirmed by the
+/// device, the bootloader refuses the images that came before.
+pub const SECURITY_VERSION: u32 = 0;
+
+/// Describes this firmware for the host, along with the hash of the installed image.
+pub fn firmware_info(manifest_hash: &[u8]) -> FirmwareInfo<'_> {
+    FirmwareInfo {
+        version: VERSION,
+        build_time: BUILD_TIME,
+        git_hash: GIT_HASH,
+        manifest_hash,
+    }
+}
+
+const fn parse(value: &str) -> u64 {
+    let bytes = value.as_bytes();
+    let mut result = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        result = result * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    result
+}
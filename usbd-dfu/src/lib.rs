@@ -41,6 +41,43 @@ pub trait Capabilities {
     const TRANSFER_SIZE: u16;
 }
 
+/// A point in time in milliseconds. It wraps around, so instants being compared must be less than
+/// 2^31 milliseconds apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Instant(u32);
+impl Instant {
+    pub const fn from_millis(ms: u32) -> Self {
+        Self(ms)
+    }
+    pub const fn as_millis(self) -> u32 {
+        self.0
+    }
+    /// The instant `ms` milliseconds after `self`.
+    pub fn after_millis(self, ms: u32) -> Self {
+        Self(self.0.wrapping_add(ms))
+    }
+    /// Milliseconds elapsed from `earlier` to `self`, or `None` if `earlier` comes after `self`.
+    pub fn checked_duration_since(self, earlier: Self) -> Option<u32> {
+        let duration = self.0.wrapping_sub(earlier.0);
+        if (duration as i32) < 0 {
+            None
+        } else {
+            Some(duration)
+        }
+    }
+}
+
+/// Source of time used by the classes to track their timeouts.
+pub trait Monotonic {
+    fn now(&self) -> Instant;
+}
+impl<F: Fn() -> Instant> Monotonic for F {
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 struct Request;
@@ -56,6 +93,7 @@ impl Request {
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     /// File is not targeted for use by this device.
     Target = 0x01,
@@ -94,24 +132,63 @@ impl From<Error> for u8 {
         err as u8
     }
 }
+impl core::convert::TryFrom<u8> for Error {
+    type Error = u8;
+
+    /// Decodes the `bStatus` of a DFU_GETSTATUS reply, `OK` (0) and unknown codes are returned as
+    /// is.
+    fn try_from(status: u8) -> core::result::Result<Self, u8> {
+        Ok(match status {
+            0x01 => Error::Target,
+            0x02 => Error::File,
+            0x03 => Error::Write,
+            0x04 => Error::Erase,
+            0x05 => Error::CheckErased,
+            0x06 => Error::Programming,
+            0x07 => Error::Verify,
+            0x08 => Error::Address,
+            0x09 => Error::NotDone,
+            0x0A => Error::Firmware,
+            0x0B => Error::Vendor,
+            0x0C => Error::UsbReset,
+            0x0D => Error::PowerOnReset,
+            0x0E => Error::Unknown,
+            0x0F => Error::StalledPkt,
+            _ => return Err(status),
+        })
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum State {
     AppIdle,
-    /// Timestamp (+/- the poll latency) when the detach request was received.
-    AppDetach(u32),
+    /// Instant at which the detach request expires if no USB reset occurred.
+    AppDetach(Instant),
     DfuIdle,
     DfuDnloadSync,
-    DfuDnloadBusy(u32),
+    /// Instant at which the poll timeout given to the host expires.
+    DfuDnloadBusy(Instant),
     DfuDnloadIdle,
     DfuManifestSync,
-    DfuManifest(u32),
+    /// Instant at which the poll timeout given to the host expires.
+    DfuManifest(Instant),
     DfuManifestWaitReset,
     DfuUploadIdle,
     DfuError(Error),
 }
 
+/// Logs state changes when the `defmt` feature is enabled.
+fn log_transition(previous: State, next: State) {
+    #[cfg(feature = "defmt")]
+    if previous != next {
+        defmt::debug!("DFU: {} -> {}", previous, next);
+    }
+    #[cfg(not(feature = "defmt"))]
+    let _ = (previous, next);
+}
+
 impl From<State> for u8 {
     fn from(state: State) -> Self {
         match state {
@@ -1,19 +1,82 @@
 use super::{
-    Capabilities, Error, Request, State, DFU_FUNCTIONAL, DFU_VERSION, USB_CLASS_DFU,
-    USB_DFU_MODE_PROTOCOL, USB_SUB_CLASS_DFU,
+    log_transition, Capabilities, Error, Instant, Monotonic, State, DFU_FUNCTIONAL, DFU_VERSION,
+    USB_CLASS_DFU, USB_DFU_MODE_PROTOCOL, USB_SUB_CLASS_DFU,
 };
 
+mod transitions;
+use transitions::{is_device_to_host, Condition, Next, Phase, Reply, ANY};
+
+/// What a device that is not manifestation tolerant does once the new firmware is in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ManifestationAction {
+    /// Resets the device, see `DeviceFirmwareUpgrade::reset`.
+    Reset,
+    /// Starts the new firmware, see `DeviceFirmwareUpgrade::jump_to_application`.
+    JumpToApplication,
+    /// Stays in dfuMANIFEST-WAIT-RESET until the host resets the device.
+    Stay,
+}
+
 pub trait DeviceFirmwareUpgrade: Capabilities {
     const POLL_TIMEOUT: u32;
 
+    /// Names of the targets the device exposes, one alternate setting each, the first one being
+    /// selected by default. Hosts such as `dfu-util` let the user pick a target by name.
+    const TARGETS: &'static [&'static str] = &[];
+
+    /// Called when the host selects another alternate setting, `alt_setting` being an index in
+    /// `TARGETS`. Subsequent uploads and downloads apply to that target.
+    fn select_target(&mut self, alt_setting: u8) -> crate::Result<()> {
+        let _ = alt_setting;
+        Ok(())
+    }
+
+    /// Time, in milliseconds, the host should wait before its next DFU_GETSTATUS while the device
+    /// is busy. Devices with long operations, such as sector erases, can report their expected
+    /// duration here.
+    fn poll_timeout(&mut self) -> u32 {
+        Self::POLL_TIMEOUT
+    }
+
+    /// Error to report when the class starts, for instance when the device was reset by a
+    /// watchdog. Takes precedence over `Error::Firmware`.
+    fn startup_error(&mut self) -> Option<Error> {
+        None
+    }
+
+    /// Description of `Error::Vendor`. While the device is in that error, DFU_GETSTATUS points the
+    /// host to it with its iString field.
+    const VENDOR_ERROR: Option<&'static str> = None;
+
     fn is_firmware_valid(&mut self) -> bool;
     fn is_transfer_complete(&mut self) -> crate::Result<bool>;
     fn is_manifestation_in_progress(&mut self) -> crate::Result<bool>;
 
+    /// Called from `DFUModeClass::poll` while a download or a manifestation is in progress, so the
+    /// device can carry on with queued work between requests. An error is reported to the host on
+    /// its next DFU_GETSTATUS.
     fn poll(&mut self) -> crate::Result<()>;
 
     fn upload(&mut self, block_number: u16, buf: &mut [u8]) -> crate::Result<usize>;
     fn download(&mut self, block_number: u16, buf: &[u8]) -> crate::Result<()>;
+
+    /// Called when the host aborts an upload or a download, or clears an error. The device is back
+    /// to dfuIDLE and the next transfer starts over from its first block.
+    fn abort(&mut self) {}
+
+    /// Called from `DFUModeClass::poll` once the manifestation is over, when the device is not
+    /// manifestation tolerant. The host got its final status by then, the action returned is run
+    /// right away.
+    fn on_manifestation_complete(&mut self) -> ManifestationAction {
+        ManifestationAction::Stay
+    }
+
+    /// Resets the device, it normally does not return.
+    fn reset(&mut self) {}
+
+    /// Starts the application, it normally does not return.
+    fn jump_to_application(&mut self) {}
 }
 
 // ================================================================================================
@@ -22,165 +85,212 @@ pub trait DeviceFirmwareUpgrade: Capabilities {
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
-pub struct DFUModeClass<H: DeviceFirmwareUpgrade, B: UsbBus> {
+/// Maximum number of targets a `DFUModeClass` exposes, a handler with more does not build.
+pub const MAX_TARGETS: usize = 4;
+
+enum Transfer<'a, 'p, 'r, B: UsbBus> {
+    In(ControlIn<'a, 'p, 'r, B>),
+    Out(ControlOut<'a, 'p, 'r, B>),
+}
+impl<B: UsbBus> Transfer<'_, '_, '_, B> {
+    fn request(&self) -> &control::Request {
+        match self {
+            Transfer::In(xfer) => xfer.request(),
+            Transfer::Out(xfer) => xfer.request(),
+        }
+    }
+}
+
+/// Answers of the handler to the questions the table asks, each is asked at most once per request.
+#[derive(Default)]
+struct Queries {
+    transfer_complete: Option<crate::Result<bool>>,
+    manifestation: Option<crate::Result<bool>>,
+}
+impl Queries {
+    fn transfer_complete<H: DeviceFirmwareUpgrade>(
+        &mut self,
+        handler: &mut H,
+    ) -> crate::Result<bool> {
+        *self
+            .transfer_complete
+            .get_or_insert_with(|| handler.is_transfer_complete())
+    }
+    fn manifestation<H: DeviceFirmwareUpgrade>(&mut self, handler: &mut H) -> crate::Result<bool> {
+        *self
+            .manifestation
+            .get_or_insert_with(|| handler.is_manifestation_in_progress())
+    }
+    fn error(&self) -> Option<Error> {
+        [self.transfer_complete, self.manifestation]
+            .iter()
+            .flatten()
+            .find_map(|answer| answer.err())
+    }
+}
+
+pub struct DFUModeClass<H: DeviceFirmwareUpgrade, B: UsbBus, M: Monotonic> {
     interface_number: InterfaceNumber,
     handler: H,
+    clock: M,
     state: State,
+    alt_setting: u8,
+    target_names: [Option<StringIndex>; MAX_TARGETS],
+    vendor_error_name: Option<StringIndex>,
+    /// Set once the host sent a DFU request to this interface.
+    has_activity: bool,
+    /// Set in dfuMANIFEST-WAIT-RESET until the handler was asked what to do next.
+    completion_pending: bool,
     _bus: core::marker::PhantomData<B>,
 }
-impl<H: DeviceFirmwareUpgrade, B: UsbBus> DFUModeClass<H, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>, mut handler: H) -> Self {
+impl<H: DeviceFirmwareUpgrade, B: UsbBus, M: Monotonic> DFUModeClass<H, B, M> {
+    /// Evaluated by `new` so the check happens once the handler is known.
+    const TARGETS_FIT: () = assert!(
+        H::TARGETS.len() <= MAX_TARGETS,
+        "DeviceFirmwareUpgrade::TARGETS has more than MAX_TARGETS targets"
+    );
+
+    pub fn new(alloc: &UsbBusAllocator<B>, mut handler: H, clock: M) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::TARGETS_FIT;
         let interface_number = alloc.interface();
-        let firmware_is_valid = handler.is_firmware_valid();
+        let mut target_names = [None; MAX_TARGETS];
+        for name in target_names.iter_mut().take(H::TARGETS.len()) {
+            *name = Some(alloc.string());
+        }
+        let vendor_error_name = H::VENDOR_ERROR.map(|_| alloc.string());
+        let state = match handler.startup_error() {
+            Some(e) => State::DfuError(e),
+            None if handler.is_firmware_valid() => State::DfuIdle,
+            None => State::DfuError(Error::Firmware),
+        };
         Self {
             interface_number,
             handler,
-            state: if firmware_is_valid {
-                State::DfuIdle
-            } else {
-                State::DfuError(Error::Firmware)
-            },
+            clock,
+            state,
+            alt_setting: 0,
+            target_names,
+            vendor_error_name,
+            has_activity: false,
+            completion_pending: false,
             _bus: core::marker::PhantomData,
         }
     }
 
-    fn idle_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_UPLOAD if H::CAN_UPLOAD && req.length <= H::TRANSFER_SIZE => {
-                self.accept_upload(xfer)
-            }
-            Request::DFU_GETSTATUS => self.accept_get_status(xfer, 1),
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            _ => self.stall_in(xfer),
-        }
-    }
-    fn idle_out(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_DNLOAD if H::CAN_DOWNLOAD && req.length > 0 => self.accept_download(xfer),
-            Request::DFU_ABORT => xfer.accept(),
-            _ => self.stall_out(xfer),
-        }
-    }
-    fn download_sync_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            Request::DFU_GETSTATUS => {
-                self.state = match self.handler.is_transfer_complete() {
-                    Ok(true) => State::DfuDnloadIdle,
-                    Ok(false) => State::DfuDnloadBusy(H::POLL_TIMEOUT),
-                    Err(e) => State::DfuError(e),
-                };
-                self.accept_get_status(xfer, H::POLL_TIMEOUT)
-            }
-            _ => self.stall_in(xfer),
-        }
-    }
+    /// Looks the request up in `TRANSITIONS`, once the timed transitions are taken, then moves to
+    /// the next state and replies.
+    fn dispatch(&mut self, xfer: Transfer<'_, '_, '_, B>) -> Result<()> {
+        self.expire();
 
-    fn download_idle_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            Request::DFU_GETSTATUS => self.accept_get_status(xfer, H::POLL_TIMEOUT),
-            _ => self.stall_in(xfer),
-        }
-    }
-    fn download_idle_out(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        let req = xfer.request();
+        let req = *xfer.request();
+        let is_in = matches!(xfer, Transfer::In(_));
+        let request = match is_device_to_host(req.request) {
+            Some(device_to_host) if device_to_host == is_in => req.request,
+            // with its data stage the wrong way, it is no DFU request
+            _ => ANY,
+        };
+        let block_len = match &xfer {
+            Transfer::Out(xfer) => Some(xfer.data().len()),
+            Transfer::In(_) => None,
+        };
 
-        let block_number = req.value;
-        let data = xfer.data();
+        let mut queries = Queries::default();
+        let (reply, next) = match self.phase() {
+            Some(phase) => transitions::lookup(phase, request, |condition| {
+                self.holds(condition, &req, block_len, &mut queries)
+            }),
+            None => (Reply::Stall, Next::Fail(Error::StalledPkt)),
+        };
 
-        match req.request {
-            Request::DFU_DNLOAD if req.length > 0 => {
-                self.state = State::DfuDnloadSync;
-                if let Err(e) = self.handler.download(block_number, data) {
-                    self.state = State::DfuError(e);
+        let mut poll_timeout = 0;
+        match next {
+            Next::Stay => {}
+            Next::Go(phase) => {
+                self.state = match phase {
+                    Phase::DfuIdle => State::DfuIdle,
+                    Phase::DfuDnloadSync => State::DfuDnloadSync,
+                    Phase::DfuDnloadBusy => {
+                        poll_timeout = self.handler.poll_timeout();
+                        State::DfuDnloadBusy(self.deadline(poll_timeout))
+                    }
+                    Phase::DfuDnloadIdle => State::DfuDnloadIdle,
+                    Phase::DfuManifestSync => State::DfuManifestSync,
+                    Phase::DfuManifest => {
+                        poll_timeout = self.handler.poll_timeout();
+                        State::DfuManifest(self.deadline(poll_timeout))
+                    }
+                    Phase::DfuManifestWaitReset => State::DfuManifestWaitReset,
+                    Phase::DfuUploadIdle => State::DfuUploadIdle,
+                    // ruled out when `transitions::TRANSITIONS` is compiled
+                    Phase::DfuError => unreachable!(),
                 }
-                xfer.accept()
             }
-            Request::DFU_DNLOAD => {
-                if let Ok(true) = self.handler.is_transfer_complete() {
-                    self.state = State::DfuManifestSync;
-                    xfer.accept()
-                } else {
-                    // sets the status to StalledPkt so we can discard the transfer error
-                    self.stall_out(xfer)
-                }
+            Next::Fail(e) => self.state = State::DfuError(e),
+            Next::HandlerError => {
+                self.state = State::DfuError(queries.error().unwrap_or(Error::Unknown))
             }
-            _ => self.stall_out(xfer),
         }
-    }
-    fn manifest_sync_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            Request::DFU_GETSTATUS => match self.handler.is_manifestation_in_progress() {
-                Ok(true) => {
-                    self.state = State::DfuManifest(H::POLL_TIMEOUT);
-                    self.accept_get_status(xfer, H::POLL_TIMEOUT)
-                }
-                Ok(false) if H::IS_MANIFESTATION_TOLERANT => {
-                    self.state = State::DfuIdle;
-                    self.accept_get_status(xfer, H::POLL_TIMEOUT)
-                }
-                _ => self.stall_in(xfer),
-            },
-            _ => self.stall_in(xfer),
-        }
-    }
-    fn upload_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_UPLOAD => self.accept_upload(xfer),
-            Request::DFU_GETSTATUS => self.accept_get_status(xfer, 1),
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            _ => self.stall_in(xfer),
-        }
-    }
-    fn upload_out(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_ABORT => {
-                self.state = State::DfuIdle;
+
+        match (reply, xfer) {
+            (Reply::SendStatus, Transfer::In(xfer)) => self.accept_get_status(xfer, poll_timeout),
+            (Reply::SendState, Transfer::In(xfer)) => self.accept_get_state(xfer),
+            (Reply::Upload, Transfer::In(xfer)) => self.accept_upload(xfer),
+            (Reply::Download, Transfer::Out(xfer)) => self.accept_download(xfer),
+            (Reply::Abort, Transfer::Out(xfer)) => {
+                self.handler.abort();
                 xfer.accept()
             }
-            _ => self.stall_out(xfer),
+            (Reply::Accept, Transfer::Out(xfer)) => xfer.accept(),
+            (_, Transfer::In(xfer)) => xfer.reject(),
+            (_, Transfer::Out(xfer)) => xfer.reject(),
         }
     }
-    fn error_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_GETSTATE => self.accept_get_state(xfer),
-            Request::DFU_GETSTATUS => self.accept_get_status(xfer, 1),
-            _ => xfer.reject(),
-        }
+
+    fn phase(&self) -> Option<Phase> {
+        Some(match self.state {
+            State::DfuIdle => Phase::DfuIdle,
+            State::DfuDnloadSync => Phase::DfuDnloadSync,
+            State::DfuDnloadBusy(_) => Phase::DfuDnloadBusy,
+            State::DfuDnloadIdle => Phase::DfuDnloadIdle,
+            State::DfuManifestSync => Phase::DfuManifestSync,
+            State::DfuManifest(_) => Phase::DfuManifest,
+            State::DfuManifestWaitReset => Phase::DfuManifestWaitReset,
+            State::DfuUploadIdle => Phase::DfuUploadIdle,
+            State::DfuError(_) => Phase::DfuError,
+            State::AppIdle | State::AppDetach(_) => return None,
+        })
     }
-    fn error_out(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        let req = xfer.request();
-        match req.request {
-            Request::DFU_CLRSTATUS => {
-                self.state = State::DfuIdle;
-                xfer.accept()
-            }
-            _ => xfer.reject(),
+
+    fn holds(
+        &mut self,
+        condition: Condition,
+        req: &control::Request,
+        block_len: Option<usize>,
+        queries: &mut Queries,
+    ) -> bool {
+        let length = usize::from(req.length);
+        let transfer_size = usize::from(H::TRANSFER_SIZE);
+        match condition {
+            Condition::CanUpload => H::CAN_UPLOAD,
+            Condition::CanDownload => H::CAN_DOWNLOAD,
+            Condition::Fits => length <= transfer_size,
+            Condition::Block => 0 < length && length <= transfer_size && block_len == Some(length),
+            Condition::NoData => length == 0,
+            Condition::TransferComplete => queries.transfer_complete(&mut self.handler) == Ok(true),
+            Condition::TransferPending => queries.transfer_complete(&mut self.handler) == Ok(false),
+            Condition::Manifesting => queries.manifestation(&mut self.handler) == Ok(true),
+            Condition::Manifested => queries.manifestation(&mut self.handler) == Ok(false),
+            Condition::Tolerant => H::IS_MANIFESTATION_TOLERANT,
+            Condition::HandlerFailed => queries.error().is_some(),
         }
     }
 
     fn accept_download(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        let req = xfer.request();
-        let block_number = req.value;
-        let data = xfer.data();
-
-        assert_eq!(usize::from(req.length), data.len());
-
-        self.state = State::DfuDnloadSync;
-        if let Err(e) = self.handler.download(block_number, data) {
+        let block_number = xfer.request().value;
+        if let Err(e) = self.handler.download(block_number, xfer.data()) {
             self.state = State::DfuError(e);
         }
-
         xfer.accept()
     }
 
@@ -189,12 +299,23 @@ impl<H: DeviceFirmwareUpgrade, B: UsbBus> DFUModeClass<H, B> {
         let block_number = req.value;
         let length = req.length.into();
 
-        self.state = State::DfuUploadIdle;
-
         xfer.accept(|buf| {
+            // the handler is given exactly what the host asked for
+            let buf = match buf.get_mut(..length) {
+                Some(buf) => buf,
+                None => {
+                    self.state = State::DfuError(Error::Unknown);
+                    return Ok(0);
+                }
+            };
             let res = self.handler.upload(block_number, buf);
 
             match res {
+                // the handler claims more than it was given
+                Ok(sz) if sz > length => {
+                    self.state = State::DfuError(Error::Unknown);
+                    Ok(0)
+                }
                 Ok(sz) => {
                     if sz < length {
                         self.state = State::DfuIdle;
@@ -218,64 +339,135 @@ impl<H: DeviceFirmwareUpgrade, B: UsbBus> DFUModeClass<H, B> {
         } else {
             0
         };
+        let string = match (self.state, self.vendor_error_name) {
+            (State::DfuError(Error::Vendor), Some(name)) => name.into(),
+            _ => 0,
+        };
         let poll_timeout = &poll_timeout.to_le_bytes()[..3];
-        let mut status = [status, 0, 0, 0, self.state.into(), 0];
+        let mut status = [status, 0, 0, 0, self.state.into(), string];
         status[1..4].copy_from_slice(poll_timeout);
 
         xfer.accept_with(&status)
     }
 
-    fn stall_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
-        self.state = State::DfuError(Error::StalledPkt);
-        xfer.reject()
+    fn target_count(&self) -> u8 {
+        core::cmp::max(1, H::TARGETS.len()) as u8
     }
-    fn stall_out(&mut self, xfer: ControlOut<B>) -> Result<()> {
-        self.state = State::DfuError(Error::StalledPkt);
-        xfer.reject()
+
+    /// Handles SET_INTERFACE, the target can only change while the interface is idle. The state is
+    /// left untouched on failure as this is a standard request.
+    fn set_alt_setting(&mut self, xfer: ControlOut<B>) -> Result<()> {
+        let alt_setting = xfer.request().value;
+        if alt_setting >= self.target_count().into() || self.state != State::DfuIdle {
+            return xfer.reject();
+        }
+        match self.handler.select_target(alt_setting as u8) {
+            Ok(()) => {
+                self.alt_setting = alt_setting as u8;
+                xfer.accept()
+            }
+            Err(_) => xfer.reject(),
+        }
     }
 
-    pub fn poll(&mut self, elapsed: u32) {
-        match &mut self.state {
-            State::DfuDnloadBusy(timeout) => match self.handler.poll() {
-                Ok(_) => {
-                    let remaining = timeout.saturating_sub(elapsed);
-                    if remaining == 0 {
-                        self.state = State::DfuDnloadSync;
-                    } else {
-                        *timeout = remaining;
-                    }
+    fn deadline(&self, timeout_ms: u32) -> Instant {
+        self.clock.now().after_millis(timeout_ms)
+    }
+    fn has_expired(&self, deadline: Instant) -> bool {
+        self.clock.now().checked_duration_since(deadline).is_some()
+    }
+
+    /// Takes the transitions that happen once the poll timeout given to the host elapsed.
+    fn expire(&mut self) {
+        match self.state {
+            State::DfuDnloadBusy(deadline) if self.has_expired(deadline) => {
+                self.state = State::DfuDnloadSync;
+            }
+            State::DfuManifest(deadline) if self.has_expired(deadline) => {
+                self.state = if H::IS_MANIFESTATION_TOLERANT {
+                    State::DfuManifestSync
+                } else {
+                    self.completion_pending = true;
+                    State::DfuManifestWaitReset
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the action the handler asks for once the manifestation, which may carry on past the
+    /// poll timeout, is over. A failure is still reported to the host.
+    fn complete_manifestation(&mut self) {
+        let result = match self.handler.is_manifestation_in_progress() {
+            Ok(true) => self.handler.poll(),
+            Ok(false) => {
+                self.completion_pending = false;
+                match self.handler.on_manifestation_complete() {
+                    ManifestationAction::Reset => self.handler.reset(),
+                    ManifestationAction::JumpToApplication => self.handler.jump_to_application(),
+                    ManifestationAction::Stay => {}
                 }
-                Err(e) => self.state = State::DfuError(e),
-            },
-            State::DfuManifest(timeout) => match self.handler.poll() {
-                Ok(_) => {
-                    let remaining = timeout.saturating_sub(elapsed);
-                    if remaining != 0 {
-                        *timeout = remaining;
-                    } else if H::IS_MANIFESTATION_TOLERANT {
-                        self.state = State::DfuManifestSync;
-                    } else {
-                        self.state = State::DfuManifestWaitReset;
-                    }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.completion_pending = false;
+            self.state = State::DfuError(e);
+        }
+    }
+
+    /// Updates the state of the driver. It should be called at least once every millisecond for
+    /// the timeouts to be accurate, more often during downloads to keep the device busy.
+    pub fn poll(&mut self) {
+        let previous = self.state;
+        match self.state {
+            State::DfuDnloadSync
+            | State::DfuDnloadBusy(_)
+            | State::DfuDnloadIdle
+            | State::DfuManifest(_) => {
+                if let Err(e) = self.handler.poll() {
+                    self.state = State::DfuError(e);
                 }
-                Err(e) => self.state = State::DfuError(e),
-            },
+            }
+            State::DfuManifestWaitReset if self.completion_pending => self.complete_manifestation(),
             _ => {}
         }
+        self.expire();
+        log_transition(previous, self.state);
     }
 
     pub fn state(&self) -> State {
         self.state
     }
+
+    /// Alternate setting, hence target, currently selected by the host.
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// True once the host sent a DFU request to this interface. A bootloader can use it to fall
+    /// back to its application when nobody talks to it.
+    pub fn has_activity(&self) -> bool {
+        self.has_activity
+    }
+
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
 }
-impl<B: UsbBus, H: DeviceFirmwareUpgrade> UsbClass<B> for DFUModeClass<H, B> {
+impl<B: UsbBus, H: DeviceFirmwareUpgrade, M: Monotonic> UsbClass<B> for DFUModeClass<H, B, M> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface(
-            self.interface_number,
-            USB_CLASS_DFU,
-            USB_SUB_CLASS_DFU,
-            USB_DFU_MODE_PROTOCOL,
-        )?;
+        for alt_setting in 0..self.target_count() {
+            writer.interface_alt(
+                self.interface_number,
+                alt_setting,
+                USB_CLASS_DFU,
+                USB_SUB_CLASS_DFU,
+                USB_DFU_MODE_PROTOCOL,
+                self.target_names[usize::from(alt_setting)],
+            )?;
+        }
 
         let attributes = {
             (if H::WILL_DETACH { 0b0000_1000 } else { 0 })
@@ -297,48 +489,68 @@ impl<B: UsbBus, H: DeviceFirmwareUpgrade> UsbClass<B> for DFUModeClass<H, B> {
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if self.vendor_error_name == Some(index) {
+            return H::VENDOR_ERROR;
+        }
+        self.target_names
+            .iter()
+            .zip(H::TARGETS)
+            .find(|(name, _)| **name == Some(index))
+            .map(|(_, target)| *target)
+    }
+
+    /// A USB reset puts every interface back to its default alternate setting, so the first target.
+    fn reset(&mut self) {
+        if self.alt_setting != 0 {
+            self.alt_setting = 0;
+            let _ = self.handler.select_target(0);
+        }
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == control::Request::GET_INTERFACE
+            && req.index == u8::from(self.interface_number).into()
+        {
+            let _ = xfer.accept_with(&[self.alt_setting]);
+            return;
+        }
         if !(req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.interface_number).into())
         {
             return;
         }
+        self.has_activity = true;
 
-        let _ = match self.state {
-            State::DfuIdle => self.idle_in(xfer),
-            State::DfuDnloadBusy(_) | State::DfuDnloadSync => self.download_sync_in(xfer),
-            State::DfuDnloadIdle => self.download_idle_in(xfer),
-            State::DfuManifest(_) | State::DfuManifestSync => self.manifest_sync_in(xfer),
-            State::DfuManifestWaitReset => xfer.accept_with_static(&[]),
-            State::DfuUploadIdle => self.upload_in(xfer),
-            State::DfuError(_) => self.error_in(xfer),
-            _ => {
-                self.state = State::DfuError(Error::StalledPkt);
-                xfer.reject()
-            }
-        };
+        let previous = self.state;
+        let _ = self.dispatch(Transfer::In(xfer));
+        log_transition(previous, self.state);
     }
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == control::Request::SET_INTERFACE
+            && req.index == u8::from(self.interface_number).into()
+        {
+            self.has_activity = true;
+            let _ = self.set_alt_setting(xfer);
+            return;
+        }
         if !(req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.interface_number).into())
         {
             return;
         }
+        self.has_activity = true;
 
-        let _ = match self.state {
-            State::DfuIdle => self.idle_out(xfer),
-            State::DfuDnloadIdle => self.download_idle_out(xfer),
-            State::DfuManifestWaitReset => xfer.accept(),
-            State::DfuUploadIdle => self.upload_out(xfer),
-            State::DfuError(_) => self.error_out(xfer),
-            _ => {
-                self.state = State::DfuError(Error::StalledPkt);
-                xfer.reject()
-            }
-        };
+        let previous = self.state;
+        let _ = self.dispatch(Transfer::Out(xfer));
+        log_transition(previous, self.state);
     }
 }
@@ -0,0 +1,185 @@
+//! DFU mode state machine, as drawn in the state diagram of DFU 1.1 (appendix A.2).
+//!
+//! Each state lists the requests it accepts, and the conditions under which it does, in the order
+//! they are checked. Every state ends with an `ANY` row catching the other requests. Timed
+//! transitions, out of dfuDNBUSY and dfuMANIFEST, happen before the table is looked up.
+
+use crate::{Error, Request};
+
+/// `State` without the data it carries, named after it.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+    DfuIdle,
+    DfuDnloadSync,
+    DfuDnloadBusy,
+    DfuDnloadIdle,
+    DfuManifestSync,
+    DfuManifest,
+    DfuManifestWaitReset,
+    DfuUploadIdle,
+    DfuError,
+}
+
+/// A condition a transition depends on, all the conditions of a row must hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Condition {
+    /// bitCanUpload is set.
+    CanUpload,
+    /// bitCanDnload is set.
+    CanDownload,
+    /// wLength is at most wTransferSize.
+    Fits,
+    /// A data stage of 1 to wTransferSize bytes, as long as wLength says.
+    Block,
+    /// wLength is 0.
+    NoData,
+    /// The handler processed the blocks received so far.
+    TransferComplete,
+    /// The handler is still processing a block.
+    TransferPending,
+    /// The handler is manifesting the image.
+    Manifesting,
+    /// The handler is done with the manifestation.
+    Manifested,
+    /// bitManifestationTolerant is set.
+    Tolerant,
+    /// The handler failed to answer one of the above.
+    HandlerFailed,
+}
+
+/// What is sent back to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Reply {
+    SendStatus,
+    SendState,
+    /// Sends the block read by the handler.
+    Upload,
+    /// Passes the block to the handler.
+    Download,
+    /// Tells the handler the transfer is over, then accepts.
+    Abort,
+    Accept,
+    Stall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Next {
+    Stay,
+    Go(Phase),
+    Fail(Error),
+    /// dfuERROR, with the error the handler reported.
+    HandlerError,
+}
+
+/// Matches any request, DFU requests only go up to 6.
+pub(super) const ANY: u8 = 0xFF;
+
+pub(super) type Transition = (Phase, u8, &'static [Condition], Reply, Next);
+
+use Condition::*;
+use Next::*;
+use Phase::*;
+use Reply::*;
+
+const DETACH: u8 = Request::DFU_DETACH;
+const DNLOAD: u8 = Request::DFU_DNLOAD;
+const UPLOAD: u8 = Request::DFU_UPLOAD;
+const GETSTATUS: u8 = Request::DFU_GETSTATUS;
+const CLRSTATUS: u8 = Request::DFU_CLRSTATUS;
+const GETSTATE: u8 = Request::DFU_GETSTATE;
+const ABORT: u8 = Request::DFU_ABORT;
+
+const STALLED: Next = Fail(Error::StalledPkt);
+
+#[rustfmt::skip]
+pub(super) const TRANSITIONS: &[Transition] = &[
+    // state                request    conditions                     reply       next
+    (DfuIdle,               DNLOAD,    &[CanDownload, Block],         Download,   Go(DfuDnloadSync)),
+    (DfuIdle,               UPLOAD,    &[CanUpload, Fits],            Upload,     Go(DfuUploadIdle)),
+    (DfuIdle,               ABORT,     &[],                           Accept,     Stay),
+    (DfuIdle,               GETSTATUS, &[],                           SendStatus, Stay),
+    (DfuIdle,               GETSTATE,  &[],                           SendState,  Stay),
+    (DfuIdle,               ANY,       &[],                           Stall,      STALLED),
+
+    (DfuDnloadSync,         GETSTATUS, &[TransferComplete],           SendStatus, Go(DfuDnloadIdle)),
+    (DfuDnloadSync,         GETSTATUS, &[TransferPending],            SendStatus, Go(DfuDnloadBusy)),
+    (DfuDnloadSync,         GETSTATUS, &[HandlerFailed],              SendStatus, HandlerError),
+    (DfuDnloadSync,         GETSTATE,  &[],                           SendState,  Stay),
+    (DfuDnloadSync,         ANY,       &[],                           Stall,      STALLED),
+
+    // the host waits for the poll timeout before its next request
+    (DfuDnloadBusy,         ANY,       &[],                           Stall,      STALLED),
+
+    (DfuDnloadIdle,         DNLOAD,    &[Block],                      Download,   Go(DfuDnloadSync)),
+    (DfuDnloadIdle,         DNLOAD,    &[NoData, TransferComplete],   Accept,     Go(DfuManifestSync)),
+    (DfuDnloadIdle,         DNLOAD,    &[NoData, HandlerFailed],      Stall,      HandlerError),
+    (DfuDnloadIdle,         DNLOAD,    &[NoData],                     Stall,      Fail(Error::NotDone)),
+    (DfuDnloadIdle,         ABORT,     &[],                           Abort,      Go(DfuIdle)),
+    (DfuDnloadIdle,         GETSTATUS, &[],                           SendStatus, Stay),
+    (DfuDnloadIdle,         GETSTATE,  &[],                           SendState,  Stay),
+    (DfuDnloadIdle,         ANY,       &[],                           Stall,      STALLED),
+
+    (DfuManifestSync,       GETSTATUS, &[Manifesting],                SendStatus, Go(DfuManifest)),
+    (DfuManifestSync,       GETSTATUS, &[Manifested, Tolerant],       SendStatus, Go(DfuIdle)),
+    // on to dfuMANIFEST-WAIT-RESET once the poll timeout elapsed
+    (DfuManifestSync,       GETSTATUS, &[Manifested],                 SendStatus, Go(DfuManifest)),
+    (DfuManifestSync,       GETSTATUS, &[HandlerFailed],              SendStatus, HandlerError),
+    (DfuManifestSync,       GETSTATE,  &[],                           SendState,  Stay),
+    (DfuManifestSync,       ANY,       &[],                           Stall,      STALLED),
+
+    (DfuManifest,           ANY,       &[],                           Stall,      STALLED),
+
+    // only a reset gets the device out of this state
+    (DfuManifestWaitReset,  GETSTATUS, &[],                           SendStatus, Stay),
+    (DfuManifestWaitReset,  GETSTATE,  &[],                           SendState,  Stay),
+    (DfuManifestWaitReset,  ANY,       &[],                           Stall,      Stay),
+
+    // a short block takes the device back to dfuIDLE
+    (DfuUploadIdle,         UPLOAD,    &[Fits],                       Upload,     Stay),
+    (DfuUploadIdle,         ABORT,     &[],                           Abort,      Go(DfuIdle)),
+    (DfuUploadIdle,         GETSTATUS, &[],                           SendStatus, Stay),
+    (DfuUploadIdle,         GETSTATE,  &[],                           SendState,  Stay),
+    (DfuUploadIdle,         ANY,       &[],                           Stall,      STALLED),
+
+    (DfuError,              GETSTATUS, &[],                           SendStatus, Stay),
+    (DfuError,              GETSTATE,  &[],                           SendState,  Stay),
+    (DfuError,              CLRSTATUS, &[],                           Abort,      Go(DfuIdle)),
+    (DfuError,              ANY,       &[],                           Stall,      Stay),
+];
+
+// dfuERROR is only entered through `Fail` and `HandlerError`, which carry the error to report
+const _: () = {
+    let mut i = 0;
+    while i < TRANSITIONS.len() {
+        assert!(
+            !matches!(TRANSITIONS[i].4, Go(DfuError)),
+            "a transition enters dfuERROR without an error, use Fail instead"
+        );
+        i += 1;
+    }
+};
+
+/// True for the requests with a data stage from the device, their direction is fixed by the
+/// specification.
+pub(super) fn is_device_to_host(request: u8) -> Option<bool> {
+    match request {
+        DETACH | DNLOAD | CLRSTATUS | ABORT => Some(false),
+        UPLOAD | GETSTATUS | GETSTATE => Some(true),
+        _ => None,
+    }
+}
+
+/// Finds the first row for `request` in `phase` whose conditions hold, `holds` being asked about
+/// each condition in turn.
+pub(super) fn lookup(
+    phase: Phase,
+    request: u8,
+    mut holds: impl FnMut(Condition) -> bool,
+) -> (Reply, Next) {
+    TRANSITIONS
+        .iter()
+        .filter(|(state, req, ..)| *state == phase && (*req == request || *req == ANY))
+        .find(|(_, _, conditions, ..)| conditions.iter().all(|&condition| holds(condition)))
+        .map_or((Stall, STALLED), |&(_, _, _, reply, next)| (reply, next))
+}
@@ -2,44 +2,108 @@ use usb_device::class_prelude::*;
 use usb_device::Result;
 
 use super::{
-    Capabilities, Request, State, DFU_FUNCTIONAL, DFU_VERSION, USB_CLASS_DFU,
-    USB_DFU_RUNTIME_PROTOCOL, USB_SUB_CLASS_DFU,
+    log_transition, Capabilities, Monotonic, Request, State, DFU_FUNCTIONAL, DFU_VERSION,
+    USB_CLASS_DFU, USB_DFU_RUNTIME_PROTOCOL, USB_SUB_CLASS_DFU,
 };
 
+/// Vendor request, addressed to the DFU runtime interface, returning the `FirmwareInfo` of the
+/// running application.
+pub const GET_FIRMWARE_INFO: u8 = 0x01;
+
+/// Version of the `FirmwareInfo` serialisation.
+pub const FIRMWARE_INFO_FORMAT: u8 = 1;
+
+/// Describes the running firmware, so that update tools can skip devices that are already current.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareInfo<'a> {
+    /// Semantic version: major, minor and patch.
+    pub version: [u16; 3],
+    /// Seconds since the Unix epoch.
+    pub build_time: u64,
+    /// Revision of the sources, as reported by the version control.
+    pub git_hash: &'a str,
+    /// Hash recorded in the manifest of the installed image.
+    pub manifest_hash: &'a [u8],
+}
+impl FirmwareInfo<'_> {
+    /// Serialises the info to `buf`, little endian:
+    /// format (u8), major, minor, patch (u16 each), build time (u64), git hash length (u8), git
+    /// hash, manifest hash length (u8), manifest hash.
+    ///
+    /// Returns the number of bytes written, or `None` if `buf` is too small.
+    pub fn write_to(&self, buf: &mut [u8]) -> Option<usize> {
+        let git_hash = self.git_hash.as_bytes();
+        let len = 1 + 6 + 8 + 1 + git_hash.len() + 1 + self.manifest_hash.len();
+        if buf.len() < len || git_hash.len() > 255 || self.manifest_hash.len() > 255 {
+            return None;
+        }
+
+        buf[0] = FIRMWARE_INFO_FORMAT;
+        for (dst, part) in buf[1..7].chunks_mut(2).zip(&self.version) {
+            dst.copy_from_slice(&part.to_le_bytes());
+        }
+        buf[7..15].copy_from_slice(&self.build_time.to_le_bytes());
+        let mut offset = 15;
+        for field in [git_hash, self.manifest_hash] {
+            buf[offset] = field.len() as u8;
+            buf[offset + 1..offset + 1 + field.len()].copy_from_slice(field);
+            offset += 1 + field.len();
+        }
+        Some(offset)
+    }
+}
+
 pub trait DeviceFirmwareUpgrade: Capabilities {
+    /// Name of the interface, reported as its string descriptor.
+    const INTERFACE_NAME: &'static str = "Device Firmware Upgrade";
+
     /// Called by the USB stack when a reset is triggered by the host.
     fn on_reset(&mut self);
 
     /// Called by the USB stack when a detach request is received by the device. If `will_detach`
-    /// is false, the device must initiate the detach-attach sequence now.
+    /// is false, the device must initiate the detach-attach sequence now. `timeout_ms` is the
+    /// host's, clamped to `DETACH_TIMEOUT`.
     fn on_detach_request(&mut self, timeout_ms: u16);
+
+    /// Called from `DFURuntimeClass::poll` when the detach timeout elapsed without a USB reset, the
+    /// device is back to appIDLE.
+    fn on_detach_timeout(&mut self) {}
+
+    /// Describes the running firmware, answered to `GET_FIRMWARE_INFO`. The request is stalled when
+    /// `None` is returned.
+    fn firmware_info(&mut self) -> Option<FirmwareInfo<'_>> {
+        None
+    }
 }
 
 #[allow(non_snake_case)]
-pub struct DFURuntimeClass<H: DeviceFirmwareUpgrade> {
+pub struct DFURuntimeClass<H: DeviceFirmwareUpgrade, M: Monotonic> {
     handler: H,
+    clock: M,
     interface_number: InterfaceNumber,
+    interface_name: StringIndex,
     state: State,
 }
 
-impl<H: DeviceFirmwareUpgrade> DFURuntimeClass<H> {
-    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, handler: H) -> Self {
+impl<H: DeviceFirmwareUpgrade, M: Monotonic> DFURuntimeClass<H, M> {
+    pub fn new<B: UsbBus>(alloc: &UsbBusAllocator<B>, handler: H, clock: M) -> Self {
         Self {
             handler,
+            clock,
             interface_number: alloc.interface(),
+            interface_name: alloc.string(),
             state: State::AppIdle,
         }
     }
 
-    /// Updates the state of the driver. Takes the number of nano-second since last update.
-    /// Ideally this method should be called once every millisecond.
-    pub fn poll(&mut self, elapsed_ms: u32) {
-        if let State::AppDetach(remaining) = &mut self.state {
-            let rem = remaining.saturating_sub(elapsed_ms);
-            if rem == 0 {
+    /// Updates the state of the driver. Ideally this method should be called once every
+    /// millisecond.
+    pub fn poll(&mut self) {
+        if let State::AppDetach(deadline) = self.state {
+            if self.clock.now().checked_duration_since(deadline).is_some() {
                 self.state = State::AppIdle;
-            } else {
-                *remaining = rem;
+                log_transition(State::AppDetach(deadline), self.state);
+                self.handler.on_detach_timeout();
             }
         }
     }
@@ -49,13 +113,24 @@ impl<H: DeviceFirmwareUpgrade> DFURuntimeClass<H> {
     }
 }
 
-impl<H: DeviceFirmwareUpgrade, B: UsbBus> UsbClass<B> for DFURuntimeClass<H> {
+impl<H: DeviceFirmwareUpgrade, M: Monotonic, B: UsbBus> UsbClass<B> for DFURuntimeClass<H, M> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface(
+        // only written by devices built with `composite_with_iads` (0xEF/0x02/0x01), where every
+        // function is described by an association
+        writer.iad(
+            self.interface_number,
+            1,
+            USB_CLASS_DFU,
+            USB_SUB_CLASS_DFU,
+            USB_DFU_RUNTIME_PROTOCOL,
+        )?;
+        writer.interface_alt(
             self.interface_number,
+            0,
             USB_CLASS_DFU,
             USB_SUB_CLASS_DFU,
             USB_DFU_RUNTIME_PROTOCOL,
+            Some(self.interface_name),
         )?;
 
         let attributes = {
@@ -78,6 +153,14 @@ impl<H: DeviceFirmwareUpgrade, B: UsbBus> UsbClass<B> for DFURuntimeClass<H> {
         Ok(())
     }
 
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.interface_name {
+            Some(H::INTERFACE_NAME)
+        } else {
+            None
+        }
+    }
+
     fn reset(&mut self) {
         if let State::AppDetach(_) = self.state {
             self.handler.on_reset();
@@ -86,23 +169,37 @@ impl<H: DeviceFirmwareUpgrade, B: UsbBus> UsbClass<B> for DFURuntimeClass<H> {
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
-        if !(req.request_type == control::RequestType::Class
-            && req.recipient == control::Recipient::Interface
+        if !(req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.interface_number).into())
         {
             return;
         }
+        if req.request_type == control::RequestType::Vendor {
+            if req.request == GET_FIRMWARE_INFO {
+                let mut buf = [0; 128];
+                let len = self
+                    .handler
+                    .firmware_info()
+                    .and_then(|info| info.write_to(&mut buf));
+                let _ = match len {
+                    Some(len) => xfer.accept_with(&buf[..len]),
+                    None => xfer.reject(),
+                };
+            }
+            return;
+        }
+        if req.request_type != control::RequestType::Class {
+            return;
+        }
 
         let _ = match req.request {
             Request::DFU_GETSTATE => xfer.accept_with(&[u8::from(self.state)]),
             Request::DFU_GETSTATUS => {
-                // there is no error status in runtime dfu
-                xfer.accept_with(&[0, 1, self.state.into(), 0])
-            }
-            _ => {
-                self.state = State::AppIdle;
-                xfer.reject()
+                // there is neither an error status nor anything to wait for in run-time mode
+                xfer.accept_with(&[0, 0, 0, 0, self.state.into(), 0])
             }
+            // stalls leave the state alone
+            _ => xfer.reject(),
         };
     }
 
@@ -115,18 +212,21 @@ impl<H: DeviceFirmwareUpgrade, B: UsbBus> UsbClass<B> for DFURuntimeClass<H> {
             return;
         }
 
+        let previous = self.state;
         if req.request == Request::DFU_DETACH {
-            let timeout_ms = xfer.request().value;
+            // the host may ask for less than advertised, not more
+            let timeout_ms = u16::min(req.value, H::DETACH_TIMEOUT);
 
-            self.state = State::AppDetach(timeout_ms.into());
+            self.state = State::AppDetach(self.clock.now().after_millis(timeout_ms.into()));
 
             // propagate the event to the handler
             self.handler.on_detach_request(timeout_ms);
 
             let _ = xfer.accept();
         } else {
-            self.state = State::AppIdle;
+            // stalls leave the state alone
             let _ = xfer.reject();
         };
+        log_transition(previous, self.state);
     }
 }
@@ -0,0 +1,57 @@
+//! The test plays the host and performs control transfers on endpoint 0 of the bus simulated by
+//! `dfu_host::sim`.
+
+#![allow(dead_code, unused_imports)]
+
+use dfu_host::sim::{self, Timeout};
+use dfu_host::TransferError;
+use usb_device::class_prelude::*;
+use usb_device::device::UsbDevice;
+
+pub use dfu_host::sim::{allocator, device, setup, SimBus, MAX_PACKET_SIZE};
+
+/// The device stalled the request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stall;
+
+fn stall(e: TransferError<Timeout>) -> Stall {
+    match e {
+        TransferError::Stall => Stall,
+        TransferError::Io(Timeout) => panic!("the device never completed the transfer"),
+    }
+}
+
+/// Performs a control transfer with a data stage from the device.
+pub fn control_in(
+    dev: &mut UsbDevice<'_, SimBus>,
+    classes: &mut [&mut dyn UsbClass<SimBus>],
+    setup: [u8; 8],
+) -> std::result::Result<Vec<u8>, Stall> {
+    sim::control_in(dev, classes, setup).map_err(stall)
+}
+
+/// Performs a control transfer with an optional data stage from the host.
+pub fn control_out(
+    dev: &mut UsbDevice<'_, SimBus>,
+    classes: &mut [&mut dyn UsbClass<SimBus>],
+    setup: [u8; 8],
+    data: &[u8],
+) -> std::result::Result<(), Stall> {
+    sim::control_out(dev, classes, setup, data).map_err(stall)
+}
+
+/// Reads a descriptor with GET_DESCRIPTOR.
+pub fn get_descriptor(
+    dev: &mut UsbDevice<'_, SimBus>,
+    classes: &mut [&mut dyn UsbClass<SimBus>],
+    descriptor_type: u8,
+    index: u8,
+) -> Vec<u8> {
+    let value = u16::from(descriptor_type) << 8 | u16::from(index);
+    let lang_id = if descriptor_type == 3 && index != 0 {
+        0x0409
+    } else {
+        0
+    };
+    control_in(dev, classes, setup(0x80, 6, value, lang_id, 0xFFFF)).expect("descriptor")
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use common::*;
+use usb_device::class_prelude::*;
+use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+use usbd_dfu::runtime::{DFURuntimeClass, DeviceFirmwareUpgrade};
+use usbd_dfu::{Capabilities, Instant};
+
+struct Runtime;
+impl Capabilities for Runtime {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = true;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = 128;
+}
+impl DeviceFirmwareUpgrade for Runtime {
+    const INTERFACE_NAME: &'static str = "DFU";
+
+    fn on_reset(&mut self) {}
+    fn on_detach_request(&mut self, _timeout_ms: u16) {}
+}
+
+/// Stands for a CDC-ACM function: two interfaces tied by an association.
+struct Cdc {
+    comm: InterfaceNumber,
+    data: InterfaceNumber,
+}
+impl Cdc {
+    fn new(alloc: &UsbBusAllocator<SimBus>) -> Self {
+        Self {
+            comm: alloc.interface(),
+            data: alloc.interface(),
+        }
+    }
+}
+impl UsbClass<SimBus> for Cdc {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.iad(self.comm, 2, 0x02, 0x02, 0x00)?;
+        writer.interface(self.comm, 0x02, 0x02, 0x00)?;
+        writer.interface(self.data, 0x0A, 0x00, 0x00)
+    }
+}
+
+const DFU_RUNTIME: [u8; 18] = [
+    // interface: number 2, DFU runtime, iInterface 4
+    9, 0x04, 2, 0, 0, 0xFE, 0x01, 0x01, 4, //
+    // DFU functional: can upload/download, manifestation tolerant, 250ms, 128 bytes, DFU 1.0
+    9, 0x21, 0b0111, 250, 0, 128, 0, 0x00, 0x01,
+];
+
+#[test]
+fn composite_device_describes_each_function_with_an_iad() {
+    let alloc = allocator();
+    let mut cdc = Cdc::new(&alloc);
+    let mut dfu = DFURuntimeClass::new(&alloc, Runtime, || Instant::from_millis(0));
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd))
+        .max_packet_size_0(MAX_PACKET_SIZE as u8)
+        .composite_with_iads()
+        .build();
+
+    let device = get_descriptor(&mut dev, &mut [&mut cdc, &mut dfu], 1, 0);
+    assert_eq!(&device[4..7], &[0xEF, 0x02, 0x01]);
+
+    let config = get_descriptor(&mut dev, &mut [&mut cdc, &mut dfu], 2, 0);
+    let mut expected = vec![
+        // configuration: 61 bytes, 3 interfaces
+        9, 0x02, 61, 0, 3, 1, 0, 0x80, 50, //
+        // CDC association and interfaces
+        8, 0x0B, 0, 2, 0x02, 0x02, 0x00, 0, //
+        9, 0x04, 0, 0, 0, 0x02, 0x02, 0x00, 0, //
+        9, 0x04, 1, 0, 0, 0x0A, 0x00, 0x00, 0, //
+        // DFU association
+        8, 0x0B, 2, 1, 0xFE, 0x01, 0x01, 0,
+    ];
+    expected.extend_from_slice(&DFU_RUNTIME);
+    assert_eq!(config, expected);
+}
+
+#[test]
+fn single_function_device_has_no_iad() {
+    let alloc = allocator();
+    let mut cdc = Cdc::new(&alloc);
+    let mut dfu = DFURuntimeClass::new(&alloc, Runtime, || Instant::from_millis(0));
+    let mut dev = device(&alloc);
+
+    let config = get_descriptor(&mut dev, &mut [&mut cdc, &mut dfu], 2, 0);
+    // the CDC stand-in writes its IAD unconditionally too, usb-device drops both
+    assert_eq!(config[2], 9 + 9 + 9 + DFU_RUNTIME.len() as u8);
+    assert_eq!(&config[27..], &DFU_RUNTIME);
+}
+
+#[test]
+fn runtime_interface_is_named() {
+    let alloc = allocator();
+    let mut dfu = DFURuntimeClass::new(&alloc, Runtime, || Instant::from_millis(0));
+    let mut dev = device(&alloc);
+
+    let name = get_descriptor(&mut dev, &mut [&mut dfu], 3, 4);
+    assert_eq!(name, [8, 0x03, b'D', 0, b'F', 0, b'U', 0]);
+}
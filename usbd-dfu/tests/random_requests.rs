@@ -0,0 +1,172 @@
+//! Random request sequences, from a host that does not follow the protocol, against a handler that
+//! fails at random. Nothing may panic and the state reported to the host stays consistent.
+
+mod common;
+
+use std::cell::Cell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use common::*;
+use usb_device::class_prelude::*;
+use usbd_dfu::mode::{DFUModeClass, DeviceFirmwareUpgrade};
+use usbd_dfu::{Capabilities, Instant, State};
+
+const SEQUENCES: u64 = 200;
+const REQUESTS: usize = 200;
+const TRANSFER_SIZE: u16 = 64;
+
+/// xorshift64*, good enough to explore request sequences and reproducible from its seed.
+struct Rng(u64);
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    /// True once every `n` times on average.
+    fn one_in(&mut self, n: u64) -> bool {
+        self.below(n) == 0
+    }
+}
+
+/// Answers at random, within what the trait allows.
+struct Chaos(Rng);
+impl Chaos {
+    fn result<T>(&mut self, value: T) -> usbd_dfu::Result<T> {
+        if self.0.one_in(8) {
+            Err(usbd_dfu::Error::Unknown)
+        } else {
+            Ok(value)
+        }
+    }
+}
+impl Capabilities for Chaos {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = true;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = TRANSFER_SIZE;
+}
+impl DeviceFirmwareUpgrade for Chaos {
+    const POLL_TIMEOUT: u32 = 5;
+
+    fn is_firmware_valid(&mut self) -> bool {
+        !self.0.one_in(4)
+    }
+    fn is_transfer_complete(&mut self) -> usbd_dfu::Result<bool> {
+        let complete = self.0.one_in(2);
+        self.result(complete)
+    }
+    fn is_manifestation_in_progress(&mut self) -> usbd_dfu::Result<bool> {
+        let in_progress = self.0.one_in(2);
+        self.result(in_progress)
+    }
+    fn poll(&mut self) -> usbd_dfu::Result<()> {
+        self.result(())
+    }
+    fn upload(&mut self, _block_number: u16, buf: &mut [u8]) -> usbd_dfu::Result<usize> {
+        assert!(buf.len() <= usize::from(TRANSFER_SIZE));
+        // sometimes claims more than it was given
+        let size = self.0.below(buf.len() as u64 + 8) as usize;
+        self.result(size)
+    }
+    fn download(&mut self, _block_number: u16, buf: &[u8]) -> usbd_dfu::Result<()> {
+        assert!(!buf.is_empty() && buf.len() <= usize::from(TRANSFER_SIZE));
+        self.result(())
+    }
+}
+
+fn is_dfu_mode(state: State) -> bool {
+    !matches!(state, State::AppIdle | State::AppDetach(_))
+}
+
+fn run(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let now = Rc::new(Cell::new(0u32));
+    let clock = {
+        let now = now.clone();
+        move || Instant::from_millis(now.get())
+    };
+
+    let alloc = allocator();
+    let mut dfu = DFUModeClass::new(&alloc, Chaos(Rng::new(!seed)), clock);
+    let mut dev = device(&alloc);
+
+    for _ in 0..REQUESTS {
+        // mostly DFU requests to the right interface, some to other recipients
+        let direction_in = rng.one_in(2);
+        let direction = (direction_in as u8) << 7;
+        let request_type = match rng.below(16) {
+            // standard, to the device
+            0 => direction,
+            // vendor, to the interface
+            1 => 0x41 | direction,
+            _ => 0x21 | direction,
+        };
+        let request = rng.below(8) as u8;
+        let value = rng.below(4) as u16;
+        let index = if rng.one_in(16) { 1 } else { 0 };
+        let length = rng.below(u64::from(2 * TRANSFER_SIZE) + 2) as u16;
+        let data: Vec<u8> = (0..length).map(|_| rng.next() as u8).collect();
+
+        let previous = dfu.state();
+        let setup = setup(request_type, request, value, index, length);
+        if direction_in {
+            let reply = control_in(&mut dev, &mut [&mut dfu as &mut dyn UsbClass<_>], setup);
+            let state = dfu.state();
+            match (request_type, request, reply) {
+                // GETSTATE
+                (0xA1, 5, Ok(reply)) if length > 0 && index == 0 => {
+                    assert_eq!(reply, [u8::from(state)]);
+                    assert_eq!(state, previous);
+                }
+                // GETSTATUS
+                (0xA1, 3, Ok(reply)) if length >= 6 && index == 0 => {
+                    assert_eq!(reply.len(), 6);
+                    assert_eq!(reply[4], u8::from(state));
+                    assert_eq!(reply[0] != 0, matches!(state, State::DfuError(_)));
+                }
+                // UPLOAD
+                (0xA1, 2, Ok(reply)) => assert!(reply.len() <= usize::from(length)),
+                _ => {}
+            }
+        } else {
+            let res = control_out(
+                &mut dev,
+                &mut [&mut dfu as &mut dyn UsbClass<_>],
+                setup,
+                &data,
+            );
+            // CLRSTATUS
+            if request_type == 0x21 && request == 4 && index == 0 && res.is_ok() {
+                assert!(matches!(previous, State::DfuError(_)));
+                assert_eq!(dfu.state(), State::DfuIdle);
+            }
+        }
+        assert!(is_dfu_mode(dfu.state()), "{:?}", dfu.state());
+
+        for _ in 0..rng.below(8) {
+            now.set(now.get() + 1);
+            dfu.poll();
+            assert!(is_dfu_mode(dfu.state()), "{:?}", dfu.state());
+        }
+    }
+}
+
+#[test]
+fn random_requests_never_panic() {
+    for seed in 0..SEQUENCES {
+        if catch_unwind(AssertUnwindSafe(|| run(seed))).is_err() {
+            panic!("sequence {} failed", seed);
+        }
+    }
+}
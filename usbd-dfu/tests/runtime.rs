@@ -0,0 +1,200 @@
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use common::*;
+use usb_device::class_prelude::*;
+use usb_device::device::UsbDevice;
+use usbd_dfu::runtime::{
+    DFURuntimeClass, DeviceFirmwareUpgrade, FirmwareInfo, FIRMWARE_INFO_FORMAT, GET_FIRMWARE_INFO,
+};
+use usbd_dfu::{Capabilities, Instant};
+
+const VENDOR_IN: u8 = 0xC1;
+const DFU_IN: u8 = 0xA1;
+const DFU_OUT: u8 = 0x21;
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_GETSTATE: u8 = 5;
+const APP_IDLE: u8 = 0;
+const APP_DETACH: u8 = 1;
+
+#[derive(Default)]
+struct Runtime {
+    manifest_hash: Option<[u8; 4]>,
+    detach_timeout: Option<u16>,
+    timed_out: bool,
+}
+impl Capabilities for Runtime {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = true;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = 128;
+}
+impl DeviceFirmwareUpgrade for Runtime {
+    fn on_reset(&mut self) {}
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.detach_timeout = Some(timeout_ms);
+    }
+    fn on_detach_timeout(&mut self) {
+        self.timed_out = true;
+    }
+
+    fn firmware_info(&mut self) -> Option<FirmwareInfo<'_>> {
+        let manifest_hash = self.manifest_hash.as_ref()?;
+        Some(FirmwareInfo {
+            version: [1, 2, 3],
+            build_time: 0x0102_0304_0506_0708,
+            git_hash: "abc123",
+            manifest_hash,
+        })
+    }
+}
+
+#[test]
+fn firmware_info_is_answered_to_the_vendor_request() {
+    let alloc = allocator();
+    let runtime = Runtime {
+        manifest_hash: Some([0xDE, 0xAD, 0xBE, 0xEF]),
+        ..Runtime::default()
+    };
+    let mut dfu = DFURuntimeClass::new(&alloc, runtime, || Instant::from_millis(0));
+    let mut dev = device(&alloc);
+
+    let info = control_in(
+        &mut dev,
+        &mut [&mut dfu],
+        setup(VENDOR_IN, GET_FIRMWARE_INFO, 0, 0, 128),
+    );
+    #[rustfmt::skip]
+    let expected = vec![
+        FIRMWARE_INFO_FORMAT,
+        1, 0, 2, 0, 3, 0,
+        8, 7, 6, 5, 4, 3, 2, 1,
+        6, b'a', b'b', b'c', b'1', b'2', b'3',
+        4, 0xDE, 0xAD, 0xBE, 0xEF,
+    ];
+    assert_eq!(info, Ok(expected));
+}
+
+#[test]
+fn firmware_info_is_stalled_when_unknown() {
+    let alloc = allocator();
+    let mut dfu = DFURuntimeClass::new(&alloc, Runtime::default(), || Instant::from_millis(0));
+    let mut dev = device(&alloc);
+
+    let info = control_in(
+        &mut dev,
+        &mut [&mut dfu],
+        setup(VENDOR_IN, GET_FIRMWARE_INFO, 0, 0, 128),
+    );
+    assert_eq!(info, Err(Stall));
+}
+
+#[test]
+fn firmware_info_must_fit_the_buffer() {
+    let info = FirmwareInfo {
+        version: [0, 1, 0],
+        build_time: 0,
+        git_hash: "0123456789abcdef0123456789abcdef01234567",
+        manifest_hash: &[0; 32],
+    };
+    let mut buf = [0; 128];
+    assert_eq!(info.write_to(&mut buf), Some(1 + 6 + 8 + 1 + 40 + 1 + 32));
+    assert_eq!(info.write_to(&mut buf[..88]), None);
+}
+
+/// Sets up a run-time class whose clock only moves when the test says so.
+fn detachable(
+    alloc: &UsbBusAllocator<SimBus>,
+) -> (
+    DFURuntimeClass<Runtime, impl Fn() -> Instant>,
+    Rc<Cell<u32>>,
+) {
+    let now = Rc::new(Cell::new(0));
+    let clock = {
+        let now = now.clone();
+        move || Instant::from_millis(now.get())
+    };
+    (DFURuntimeClass::new(alloc, Runtime::default(), clock), now)
+}
+
+fn get_state(dev: &mut UsbDevice<'_, SimBus>, dfu: &mut dyn UsbClass<SimBus>) -> u8 {
+    let state = control_in(dev, &mut [dfu], setup(DFU_IN, DFU_GETSTATE, 0, 0, 1));
+    state.unwrap()[0]
+}
+
+#[test]
+fn status_is_six_bytes_long() {
+    let alloc = allocator();
+    let (mut dfu, _) = detachable(&alloc);
+    let mut dev = device(&alloc);
+
+    let status = control_in(
+        &mut dev,
+        &mut [&mut dfu],
+        setup(DFU_IN, DFU_GETSTATUS, 0, 0, 6),
+    );
+    assert_eq!(status, Ok(vec![0, 0, 0, 0, APP_IDLE, 0]));
+}
+
+#[test]
+fn detach_timeout_is_clamped_to_the_advertised_one() {
+    let alloc = allocator();
+    let (mut dfu, now) = detachable(&alloc);
+    let mut dev = device(&alloc);
+
+    let detach = setup(DFU_OUT, DFU_DETACH, 1000, 0, 0);
+    assert_eq!(control_out(&mut dev, &mut [&mut dfu], detach, &[]), Ok(()));
+    assert_eq!(dfu.handler().detach_timeout, Some(Runtime::DETACH_TIMEOUT));
+
+    now.set(u32::from(Runtime::DETACH_TIMEOUT) - 1);
+    dfu.poll();
+    assert_eq!(get_state(&mut dev, &mut dfu), APP_DETACH);
+    now.set(u32::from(Runtime::DETACH_TIMEOUT));
+    dfu.poll();
+    assert_eq!(get_state(&mut dev, &mut dfu), APP_IDLE);
+}
+
+#[test]
+fn detach_timeout_returns_to_app_idle() {
+    let alloc = allocator();
+    let (mut dfu, now) = detachable(&alloc);
+    let mut dev = device(&alloc);
+
+    let detach = setup(DFU_OUT, DFU_DETACH, 100, 0, 0);
+    assert_eq!(control_out(&mut dev, &mut [&mut dfu], detach, &[]), Ok(()));
+    assert_eq!(dfu.handler().detach_timeout, Some(100));
+
+    now.set(99);
+    dfu.poll();
+    assert!(!dfu.handler().timed_out);
+    now.set(100);
+    dfu.poll();
+    assert!(dfu.handler().timed_out);
+    assert_eq!(get_state(&mut dev, &mut dfu), APP_IDLE);
+}
+
+#[test]
+fn stalled_requests_leave_the_state_alone() {
+    let alloc = allocator();
+    let (mut dfu, _) = detachable(&alloc);
+    let mut dev = device(&alloc);
+
+    let detach = setup(DFU_OUT, DFU_DETACH, 100, 0, 0);
+    assert_eq!(control_out(&mut dev, &mut [&mut dfu], detach, &[]), Ok(()));
+
+    let dnload = setup(DFU_OUT, DFU_DNLOAD, 0, 0, 4);
+    assert_eq!(
+        control_out(&mut dev, &mut [&mut dfu], dnload, &[0; 4]),
+        Err(Stall)
+    );
+    let upload = setup(DFU_IN, 2, 0, 0, 64);
+    assert_eq!(control_in(&mut dev, &mut [&mut dfu], upload), Err(Stall));
+    assert_eq!(get_state(&mut dev, &mut dfu), APP_DETACH);
+    assert!(!dfu.handler().timed_out);
+}
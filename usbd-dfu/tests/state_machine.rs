@@ -0,0 +1,574 @@
+//! Every DFU request in every state of the DFU mode, checked against the state diagram of DFU 1.1.
+
+mod common;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use common::*;
+use usb_device::class_prelude::*;
+use usb_device::device::UsbDevice;
+use usbd_dfu::mode::{DFUModeClass, DeviceFirmwareUpgrade, ManifestationAction};
+use usbd_dfu::{Capabilities, Instant, Monotonic, State};
+
+const DETACH: u8 = 0;
+const DNLOAD: u8 = 1;
+const UPLOAD: u8 = 2;
+const GETSTATUS: u8 = 3;
+const CLRSTATUS: u8 = 4;
+const GETSTATE: u8 = 5;
+const ABORT: u8 = 6;
+/// Not a DFU request.
+const UNKNOWN: u8 = 7;
+
+const IDLE: u8 = 2;
+const DNLOAD_SYNC: u8 = 3;
+const DNBUSY: u8 = 4;
+const DNLOAD_IDLE: u8 = 5;
+const MANIFEST_SYNC: u8 = 6;
+const MANIFEST: u8 = 7;
+const MANIFEST_WAIT_RESET: u8 = 8;
+const UPLOAD_IDLE: u8 = 9;
+const ERROR: u8 = 10;
+
+const TRANSFER_SIZE: u16 = 64;
+const POLL_TIMEOUT: u32 = 20;
+const VENDOR_ERROR: &str = "Watchdog reset";
+
+/// Answers as told by the test.
+struct Script<const TOLERANT: bool> {
+    transfer_complete: usbd_dfu::Result<bool>,
+    manifesting: usbd_dfu::Result<bool>,
+    /// The manifestation is over on the next `poll`, before the host asks for a status.
+    manifests_in_poll: bool,
+    /// Times the manifestation finished in `poll`.
+    manifested: usize,
+    aborted: usize,
+    /// Target selected by the class.
+    target: u8,
+    startup_error: Option<usbd_dfu::Error>,
+    completion: ManifestationAction,
+    /// Actions run by the class, in order.
+    actions: Vec<ManifestationAction>,
+}
+impl<const TOLERANT: bool> Capabilities for Script<TOLERANT> {
+    const WILL_DETACH: bool = false;
+    const IS_MANIFESTATION_TOLERANT: bool = TOLERANT;
+    const CAN_UPLOAD: bool = true;
+    const CAN_DOWNLOAD: bool = true;
+    const DETACH_TIMEOUT: u16 = 250;
+    const TRANSFER_SIZE: u16 = TRANSFER_SIZE;
+}
+impl<const TOLERANT: bool> DeviceFirmwareUpgrade for Script<TOLERANT> {
+    const POLL_TIMEOUT: u32 = POLL_TIMEOUT;
+    const TARGETS: &'static [&'static str] = &["Firmware", "Diagnostics"];
+    const VENDOR_ERROR: Option<&'static str> = Some(VENDOR_ERROR);
+
+    fn select_target(&mut self, alt_setting: u8) -> usbd_dfu::Result<()> {
+        self.target = alt_setting;
+        Ok(())
+    }
+
+    fn startup_error(&mut self) -> Option<usbd_dfu::Error> {
+        self.startup_error
+    }
+    fn is_firmware_valid(&mut self) -> bool {
+        true
+    }
+    fn is_transfer_complete(&mut self) -> usbd_dfu::Result<bool> {
+        self.transfer_complete
+    }
+    fn is_manifestation_in_progress(&mut self) -> usbd_dfu::Result<bool> {
+        self.manifesting
+    }
+    fn poll(&mut self) -> usbd_dfu::Result<()> {
+        if self.manifests_in_poll && self.manifesting == Ok(true) {
+            self.manifesting = Ok(false);
+            self.manifested += 1;
+        }
+        Ok(())
+    }
+    fn upload(&mut self, _block_number: u16, buf: &mut [u8]) -> usbd_dfu::Result<usize> {
+        Ok(buf.len())
+    }
+    fn download(&mut self, _block_number: u16, _buf: &[u8]) -> usbd_dfu::Result<()> {
+        Ok(())
+    }
+    fn abort(&mut self) {
+        self.aborted += 1;
+    }
+    fn on_manifestation_complete(&mut self) -> ManifestationAction {
+        self.completion
+    }
+    fn reset(&mut self) {
+        self.actions.push(ManifestationAction::Reset);
+    }
+    fn jump_to_application(&mut self) {
+        self.actions.push(ManifestationAction::JumpToApplication);
+    }
+}
+
+#[derive(Clone, Default)]
+struct Clock(Rc<Cell<u32>>);
+impl Monotonic for Clock {
+    fn now(&self) -> Instant {
+        Instant::from_millis(self.0.get())
+    }
+}
+
+struct Device<'a, const TOLERANT: bool> {
+    dev: UsbDevice<'a, SimBus>,
+    dfu: DFUModeClass<Script<TOLERANT>, SimBus, Clock>,
+    clock: Clock,
+}
+impl<'a, const TOLERANT: bool> Device<'a, TOLERANT> {
+    fn new(alloc: &'a UsbBusAllocator<SimBus>) -> Self {
+        Self::starting_with(alloc, None)
+    }
+    fn starting_with(
+        alloc: &'a UsbBusAllocator<SimBus>,
+        startup_error: Option<usbd_dfu::Error>,
+    ) -> Self {
+        let clock = Clock::default();
+        let script = Script {
+            transfer_complete: Ok(true),
+            manifesting: Ok(true),
+            manifests_in_poll: false,
+            manifested: 0,
+            aborted: 0,
+            target: 0,
+            startup_error,
+            completion: ManifestationAction::Stay,
+            actions: Vec::new(),
+        };
+        let dfu = DFUModeClass::new(alloc, script, clock.clone());
+        Self {
+            dev: device(alloc),
+            dfu,
+            clock,
+        }
+    }
+
+    fn control_in(&mut self, request: u8, length: u16) -> Result<Vec<u8>, Stall> {
+        let setup = setup(0xA1, request, 0, 0, length);
+        control_in(&mut self.dev, &mut [&mut self.dfu], setup)
+    }
+    fn control_out(&mut self, request: u8, data: &[u8]) -> Result<(), Stall> {
+        let setup = setup(0x21, request, 0, 0, data.len() as u16);
+        control_out(&mut self.dev, &mut [&mut self.dfu], setup, data)
+    }
+    /// Sends `request` the way the specification defines it.
+    fn request(&mut self, request: u8) -> Result<(), Stall> {
+        match request {
+            DNLOAD => self.control_out(request, &[0xA5; 16]),
+            UPLOAD => self.control_in(request, TRANSFER_SIZE).map(drop),
+            GETSTATUS => self.control_in(request, 6).map(drop),
+            GETSTATE => self.control_in(request, 1).map(drop),
+            _ => self.control_out(request, &[]),
+        }
+    }
+    /// Sends `request` with its data stage the wrong way.
+    fn reversed_request(&mut self, request: u8) -> Result<(), Stall> {
+        match request {
+            UPLOAD | GETSTATUS | GETSTATE => self.control_out(request, &[]),
+            _ => self.control_in(request, 6).map(drop),
+        }
+    }
+
+    fn wait(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.clock.0.set(self.clock.0.get() + 1);
+            self.dfu.poll();
+        }
+    }
+    fn state(&self) -> u8 {
+        self.dfu.state().into()
+    }
+    fn script(&mut self) -> &mut Script<TOLERANT> {
+        self.dfu.handler()
+    }
+
+    /// Takes the device from dfuIDLE to `state`.
+    fn enter(&mut self, state: u8) {
+        match state {
+            IDLE => {}
+            DNLOAD_SYNC => self.control_out(DNLOAD, &[0xA5; 16]).unwrap(),
+            DNBUSY => {
+                self.enter(DNLOAD_SYNC);
+                self.script().transfer_complete = Ok(false);
+                self.control_in(GETSTATUS, 6).unwrap();
+                self.script().transfer_complete = Ok(true);
+            }
+            DNLOAD_IDLE => {
+                self.enter(DNLOAD_SYNC);
+                self.control_in(GETSTATUS, 6).unwrap();
+            }
+            MANIFEST_SYNC => {
+                self.enter(DNLOAD_IDLE);
+                self.control_out(DNLOAD, &[]).unwrap();
+            }
+            MANIFEST => {
+                self.enter(MANIFEST_SYNC);
+                self.control_in(GETSTATUS, 6).unwrap();
+            }
+            MANIFEST_WAIT_RESET => {
+                assert!(!TOLERANT);
+                self.enter(MANIFEST);
+                self.wait(POLL_TIMEOUT);
+            }
+            UPLOAD_IDLE => {
+                self.control_in(UPLOAD, TRANSFER_SIZE).unwrap();
+            }
+            ERROR => {
+                self.control_out(UNKNOWN, &[]).unwrap_err();
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(self.state(), state);
+    }
+}
+
+/// Whether `request` is accepted in `state`, and the state it leads to, for a device that is
+/// manifestation tolerant unless in dfuMANIFEST-WAIT-RESET. The device completes blocks right
+/// away, takes a while to manifest and uploads full blocks.
+fn expected(state: u8, request: u8) -> (bool, u8) {
+    match (state, request) {
+        (IDLE, DNLOAD) => (true, DNLOAD_SYNC),
+        (IDLE, UPLOAD) => (true, UPLOAD_IDLE),
+        (IDLE, ABORT) => (true, IDLE),
+        (DNLOAD_SYNC, GETSTATUS) => (true, DNLOAD_IDLE),
+        (DNLOAD_IDLE, DNLOAD) => (true, DNLOAD_SYNC),
+        (DNLOAD_IDLE, ABORT) => (true, IDLE),
+        (MANIFEST_SYNC, GETSTATUS) => (true, MANIFEST),
+        (UPLOAD_IDLE, UPLOAD) => (true, UPLOAD_IDLE),
+        (UPLOAD_IDLE, ABORT) => (true, IDLE),
+        (ERROR, CLRSTATUS) => (true, IDLE),
+        // a busy device does not answer before its poll timeout
+        (DNBUSY, _) | (MANIFEST, _) => (false, ERROR),
+        (_, GETSTATUS) | (_, GETSTATE) => (true, state),
+        (MANIFEST_WAIT_RESET, _) | (ERROR, _) => (false, state),
+        _ => (false, ERROR),
+    }
+}
+
+const STATES: [u8; 8] = [
+    IDLE,
+    DNLOAD_SYNC,
+    DNBUSY,
+    DNLOAD_IDLE,
+    MANIFEST_SYNC,
+    MANIFEST,
+    UPLOAD_IDLE,
+    ERROR,
+];
+
+fn check<const TOLERANT: bool>(state: u8) {
+    for request in DETACH..=UNKNOWN {
+        let alloc = allocator();
+        let mut device = Device::<TOLERANT>::new(&alloc);
+        device.enter(state);
+
+        let accepted = device.request(request).is_ok();
+        assert_eq!(
+            (accepted, device.state()),
+            expected(state, request),
+            "request {} in state {}",
+            request,
+            state
+        );
+    }
+}
+
+#[test]
+fn every_request_in_every_state() {
+    for state in STATES {
+        check::<true>(state);
+    }
+    check::<false>(MANIFEST_WAIT_RESET);
+}
+
+#[test]
+fn requests_with_their_data_stage_the_wrong_way_are_stalled() {
+    for state in STATES {
+        for request in DETACH..=ABORT {
+            let alloc = allocator();
+            let mut device = Device::<true>::new(&alloc);
+            device.enter(state);
+
+            assert!(device.reversed_request(request).is_err());
+            assert_eq!(
+                device.state(),
+                ERROR,
+                "request {} in state {}",
+                request,
+                state
+            );
+        }
+    }
+}
+
+#[test]
+fn abort_ends_a_download() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(DNLOAD_IDLE);
+
+    device.control_out(ABORT, &[]).unwrap();
+    assert_eq!(device.state(), IDLE);
+    assert_eq!(device.script().aborted, 1);
+}
+
+#[test]
+fn clearing_an_error_ends_the_transfer() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(ERROR);
+
+    device.control_out(CLRSTATUS, &[]).unwrap();
+    assert_eq!(device.state(), IDLE);
+    assert_eq!(device.script().aborted, 1);
+}
+
+#[test]
+fn poll_timeout_is_only_reported_while_busy() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, IDLE, 0]);
+
+    device.enter(UPLOAD_IDLE);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, UPLOAD_IDLE, 0]);
+
+    device.control_out(ABORT, &[]).unwrap();
+    device.enter(DNLOAD_SYNC);
+    device.script().transfer_complete = Ok(false);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, POLL_TIMEOUT as u8, 0, 0, DNBUSY, 0]);
+}
+
+#[test]
+fn busy_device_is_back_in_sync_after_its_poll_timeout() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(DNBUSY);
+
+    device.wait(POLL_TIMEOUT - 1);
+    assert_eq!(device.state(), DNBUSY);
+    device.wait(1);
+    assert_eq!(device.state(), DNLOAD_SYNC);
+
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, DNLOAD_IDLE, 0]);
+}
+
+#[test]
+fn handler_errors_are_reported_in_the_status() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(DNLOAD_SYNC);
+
+    device.script().transfer_complete = Err(usbd_dfu::Error::Verify);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0x07, 0, 0, 0, ERROR, 0]);
+}
+
+#[test]
+fn end_of_image_before_the_last_block_is_done() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(DNLOAD_IDLE);
+
+    device.script().transfer_complete = Ok(false);
+    assert!(device.control_out(DNLOAD, &[]).is_err());
+    assert_eq!(
+        device.dfu.state(),
+        State::DfuError(usbd_dfu::Error::NotDone)
+    );
+}
+
+#[test]
+fn oversized_blocks_are_stalled() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+
+    let block = [0; TRANSFER_SIZE as usize + 1];
+    assert!(device.control_out(DNLOAD, &block).is_err());
+    assert_eq!(device.state(), ERROR);
+}
+
+#[test]
+fn manifestation_ends_in_idle_when_tolerant() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(MANIFEST);
+
+    device.wait(POLL_TIMEOUT);
+    assert_eq!(device.state(), MANIFEST_SYNC);
+    device.script().manifesting = Ok(false);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, IDLE, 0]);
+}
+
+#[test]
+fn manifestation_can_end_in_poll() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.enter(MANIFEST);
+
+    device.script().manifests_in_poll = true;
+    device.wait(POLL_TIMEOUT);
+    assert_eq!(device.script().manifested, 1);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, IDLE, 0]);
+    assert_eq!(device.script().manifested, 1);
+}
+
+#[test]
+fn manifestation_waits_for_a_reset_otherwise() {
+    let alloc = allocator();
+    let mut device = Device::<false>::new(&alloc);
+    device.enter(MANIFEST_SYNC);
+
+    device.script().manifesting = Ok(false);
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, POLL_TIMEOUT as u8, 0, 0, MANIFEST, 0]);
+    device.wait(POLL_TIMEOUT);
+    assert_eq!(device.state(), MANIFEST_WAIT_RESET);
+
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, MANIFEST_WAIT_RESET, 0]);
+}
+
+#[test]
+fn completion_action_runs_once_the_manifestation_is_over() {
+    for action in [
+        ManifestationAction::Reset,
+        ManifestationAction::JumpToApplication,
+    ] {
+        let alloc = allocator();
+        let mut device = Device::<false>::new(&alloc);
+        device.script().completion = action;
+        device.enter(MANIFEST_WAIT_RESET);
+
+        // still writing the image past the poll timeout
+        device.wait(POLL_TIMEOUT);
+        assert!(device.script().actions.is_empty());
+
+        device.script().manifesting = Ok(false);
+        device.wait(POLL_TIMEOUT);
+        assert_eq!(device.script().actions, [action]);
+        assert_eq!(device.state(), MANIFEST_WAIT_RESET);
+    }
+}
+
+#[test]
+fn completion_action_waits_for_the_final_status() {
+    let alloc = allocator();
+    let mut device = Device::<false>::new(&alloc);
+    device.script().completion = ManifestationAction::Reset;
+    device.enter(MANIFEST_SYNC);
+    device.script().manifesting = Ok(false);
+    device.wait(POLL_TIMEOUT);
+    assert!(device.script().actions.is_empty());
+
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, POLL_TIMEOUT as u8, 0, 0, MANIFEST, 0]);
+    device.wait(POLL_TIMEOUT - 1);
+    assert!(device.script().actions.is_empty());
+    device.wait(2);
+    assert_eq!(device.script().actions, [ManifestationAction::Reset]);
+}
+
+#[test]
+fn staying_waits_for_the_host_to_reset() {
+    let alloc = allocator();
+    let mut device = Device::<false>::new(&alloc);
+    device.enter(MANIFEST_WAIT_RESET);
+    device.script().manifesting = Ok(false);
+
+    device.wait(POLL_TIMEOUT);
+    assert!(device.script().actions.is_empty());
+    assert_eq!(device.state(), MANIFEST_WAIT_RESET);
+}
+
+#[test]
+fn failed_manifestation_runs_no_action() {
+    let alloc = allocator();
+    let mut device = Device::<false>::new(&alloc);
+    device.script().completion = ManifestationAction::JumpToApplication;
+    device.enter(MANIFEST_WAIT_RESET);
+    device.script().manifesting = Err(usbd_dfu::Error::Verify);
+
+    device.wait(1);
+    assert!(device.script().actions.is_empty());
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [usbd_dfu::Error::Verify.into(), 0, 0, 0, ERROR, 0]);
+}
+
+#[test]
+fn tolerant_devices_run_no_action() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    device.script().completion = ManifestationAction::Reset;
+    device.enter(MANIFEST);
+    device.script().manifesting = Ok(false);
+
+    device.wait(POLL_TIMEOUT);
+    device.control_in(GETSTATUS, 6).unwrap();
+    device.wait(POLL_TIMEOUT);
+    assert_eq!(device.state(), IDLE);
+    assert!(device.script().actions.is_empty());
+}
+
+#[test]
+fn vendor_error_is_described_by_a_string() {
+    let alloc = allocator();
+    let mut device = Device::<true>::starting_with(&alloc, Some(usbd_dfu::Error::Vendor));
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(
+        status[..5],
+        [usbd_dfu::Error::Vendor.into(), 0, 0, 0, ERROR]
+    );
+    assert_ne!(status[5], 0);
+
+    let name = get_descriptor(&mut device.dev, &mut [&mut device.dfu], 3, status[5]);
+    let expected: Vec<u8> = VENDOR_ERROR
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    assert_eq!(name[2..], expected[..]);
+
+    device.control_out(CLRSTATUS, &[]).unwrap();
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(status, [0, 0, 0, 0, IDLE, 0]);
+}
+
+#[test]
+fn other_errors_have_no_string() {
+    let alloc = allocator();
+    let mut device = Device::<true>::starting_with(&alloc, Some(usbd_dfu::Error::PowerOnReset));
+    let status = device.control_in(GETSTATUS, 6).unwrap();
+    assert_eq!(
+        status,
+        [usbd_dfu::Error::PowerOnReset.into(), 0, 0, 0, ERROR, 0]
+    );
+}
+
+#[test]
+fn usb_reset_selects_the_first_target() {
+    let alloc = allocator();
+    let mut device = Device::<true>::new(&alloc);
+    let set_interface = setup(0x01, 11, 1, 0, 0);
+    control_out(&mut device.dev, &mut [&mut device.dfu], set_interface, &[]).unwrap();
+    assert_eq!(device.dfu.alt_setting(), 1);
+    assert_eq!(device.script().target, 1);
+
+    UsbClass::reset(&mut device.dfu);
+    assert_eq!(device.dfu.alt_setting(), 0);
+    assert_eq!(device.script().target, 0);
+    let get_interface = setup(0x81, 10, 0, 0, 1);
+    let alt_setting = control_in(&mut device.dev, &mut [&mut device.dfu], get_interface);
+    assert_eq!(alt_setting, Ok(vec![0]));
+}